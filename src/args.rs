@@ -2,7 +2,15 @@ use clap::Parser;
 
 /// Chroot helper for CachyOS
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Environment:\n  CACHY_LUKS_PASSPHRASE  Passphrase for LUKS devices without a key file, for unattended \
+                  runs. It is never logged, but other processes of the same user can read it, \
+                  prefer --luks-key-file where possible"
+)]
 pub struct Args {
     /// Allow running the program without root permissions
     #[arg(long = "skip-root-check", default_value_t = false)]
@@ -16,4 +24,194 @@ pub struct Args {
     /// mounted
     #[arg(long = "no-auto-mount", default_value_t = false)]
     pub no_auto_mount: bool,
+
+    /// Only mount the root partition and chroot, implies --no-auto-mount and skips the prompt for
+    /// additional partitions
+    #[arg(long = "mount-root-only", default_value_t = false)]
+    pub mount_root_only: bool,
+
+    /// Additional comma separated mount options for the root partition (e.g. rescue,nodatacow)
+    #[arg(long = "mount-options", value_name = "OPTIONS")]
+    pub mount_options: Option<String>,
+
+    /// Emit a machine readable {"error":"...","code":N} object on stderr when exiting on an error
+    #[arg(long = "json", default_value_t = false)]
+    pub json: bool,
+
+    /// List which features are available on this system and exit
+    #[arg(long = "features", default_value_t = false)]
+    pub features: bool,
+
+    /// Offer to chroot into a root partition that is already mounted on the live system instead
+    /// of mounting it again
+    #[arg(long = "reuse-existing-mounts", default_value_t = false)]
+    pub reuse_existing_mounts: bool,
+
+    /// Disable BTRFS support, BTRFS partitions are treated as unsupported and skipped
+    #[arg(long = "no-btrfs", default_value_t = false)]
+    pub no_btrfs: bool,
+
+    /// Disable LUKS support, LUKS encrypted partitions are treated as unsupported and skipped
+    #[arg(long = "no-luks", default_value_t = false)]
+    pub no_luks: bool,
+
+    /// Generate an fstab from the mounted partitions with genfstab and offer to write it to the
+    /// root's /etc/fstab
+    #[arg(long = "genfstab", default_value_t = false)]
+    pub genfstab: bool,
+
+    /// Chroot into a subdirectory of the root partition that contains the actual OS tree
+    #[arg(long = "root-dir", value_name = "SUBPATH")]
+    pub root_dir: Option<String>,
+
+    /// Leave partitions with the filesystem type FSTYPE (as reported by lsblk, e.g. ntfs or exfat)
+    /// out of every partition list, including the root selection. Partitions without a detected
+    /// filesystem can't be excluded this way. Can be given multiple times
+    #[arg(
+        long = "exclude-fstype",
+        value_name = "FSTYPE",
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    pub exclude_fstypes: Vec<String>,
+
+    /// Filesystem type of the root partition to use instead of the detected one, e.g. when lsblk
+    /// reports it wrong or not at all, it is also passed to mount with -t. LUKS encrypted roots
+    /// are still unlocked first, the type applies to the partition that gets mounted
+    #[arg(long = "root-fstype", value_name = "FSTYPE")]
+    pub root_fstype: Option<String>,
+
+    /// Select the largest Linux partition as root instead of asking, partitions whose GPT
+    /// partition type marks them as root are preferred and ones marked as ESP, /boot, /home,
+    /// /srv or /var are skipped. LUKS encrypted partitions are still unlocked
+    #[arg(long = "select-by-size", default_value_t = false)]
+    pub select_by_size: bool,
+
+    /// Select the partition whose GPT partition type marks it as root for this architecture
+    /// (Discoverable Partitions Specification) instead of asking, asks when there is none or more
+    /// than one. LUKS encrypted partitions are still unlocked
+    #[arg(long = "auto-root", default_value_t = false)]
+    pub auto_root: bool,
+
+    /// Write an fstab describing the mounted partitions by UUID and BTRFS subvolume ID to PATH on
+    /// the live system before chrooting, unlike --genfstab this does not need genfstab
+    #[arg(long = "output-fstab", value_name = "PATH")]
+    pub output_fstab: Option<String>,
+
+    /// Attach a raw disk image to a loop device so the partitions inside it can be selected, the
+    /// loop device is detached again afterwards
+    #[arg(long = "image", value_name = "PATH")]
+    pub image: Option<String>,
+
+    /// When the root is still busy on cleanup, offer to kill the processes using it (with fuser)
+    /// instead of falling back to a lazy unmount
+    #[arg(long = "force-umount", default_value_t = false)]
+    pub force_umount: bool,
+
+    /// Open the LUKS device DEVICE (a path, UUID=, PARTUUID=, LABEL= or PARTLABEL=) with the key
+    /// file at PATH instead of asking for the passphrase, can be given multiple times
+    #[arg(long = "luks-key-file", value_name = "DEVICE:PATH")]
+    pub luks_key_files: Vec<String>,
+
+    /// Open every LUKS device without its own --luks-key-file with the key file at PATH
+    #[arg(long = "luks-key-file-all", value_name = "PATH")]
+    pub luks_key_file_all: Option<String>,
+
+    /// Root partition to use instead of asking: a device path, UUID=, PARTUUID=, LABEL=,
+    /// PARTLABEL= or a bare UUID or label. An encrypted root is unlocked and its decrypted device
+    /// is used.
+    #[arg(long = "root", value_name = "DEVICE")]
+    pub root: Option<String>,
+
+    /// Leave the mounted partitions, opened LUKS devices and attached images in place on exit and
+    /// print how to clean them up, for debugging
+    #[arg(long = "no-cleanup", default_value_t = false)]
+    pub no_cleanup: bool,
+
+    /// Run in a new private mount namespace (with unshare --mount), so the mounts are not visible
+    /// to the rest of the system and go away with the namespace when the program exits, even if it
+    /// crashes. Opened LUKS devices and attached images are not bound to the namespace
+    #[arg(long = "mount-namespace", default_value_t = false)]
+    pub mount_namespace: bool,
+
+    /// Extra comma separated mount options for the device DEVICE (a path, UUID=, PARTUUID=, LABEL=
+    /// or PARTLABEL=), added to the options used when it is mounted from /etc/fstab or
+    /// interactively. Can be given multiple times
+    #[arg(long = "device-options", value_name = "DEVICE:OPTIONS")]
+    pub device_options: Vec<String>,
+
+    /// Also mount the OS root on DEVICE (a path, UUID=, PARTUUID=, LABEL= or PARTLABEL=) at PATH
+    /// below the root, e.g. to copy files between installs. It is unlocked and its subvolume
+    /// selected like the root, but not chrooted into. Can be given multiple times
+    #[arg(long = "also-mount-root", value_name = "DEVICE:PATH")]
+    pub also_mount_roots: Vec<String>,
+
+    /// Continue and chroot even if the root partition does not look like a Linux root, e.g. when
+    /// it has no /etc/fstab
+    #[arg(long = "keep-going", default_value_t = false)]
+    pub keep_going: bool,
+
+    /// Enter the chroot as USER with a login shell (su - USER) instead of as root, the user has to
+    /// exist in the root's /etc/passwd
+    #[arg(long = "user", value_name = "USER")]
+    pub user: Option<String>,
+
+    /// Extra arguments passed to arch-chroot before the root when entering the chroot, split on
+    /// whitespace (e.g. "-r"), for arch-chroot options this tool has no flag for
+    #[arg(long = "arch-chroot-args", value_name = "ARGS", allow_hyphen_values = true)]
+    pub arch_chroot_args: Option<String>,
+
+    /// Re-read the partition tables (with partprobe, or blockdev when it is not installed) before
+    /// listing partitions, e.g. right after repartitioning
+    #[arg(long = "partition-scan", default_value_t = false)]
+    pub partition_scan: bool,
+
+    /// Wait until a device matching DEVICE (a path, UUID=, PARTUUID=, LABEL= or PARTLABEL=) shows
+    /// up before listing partitions, e.g. for slow external enclosures. Can be given multiple
+    /// times
+    #[arg(long = "wait-for-device", value_name = "DEVICE")]
+    pub wait_for_devices: Vec<String>,
+
+    /// Seconds to wait for the devices given with --wait-for-device before giving up
+    #[arg(long = "wait-timeout", value_name = "SECONDS", default_value_t = 30)]
+    pub wait_timeout: u64,
+
+    /// Regenerate the initramfs inside the chroot after the interactive session, with
+    /// limine-mkinitcpio, mkinitcpio or dracut depending on what is installed
+    #[arg(long = "regen-initramfs", default_value_t = false)]
+    pub regen_initramfs: bool,
+
+    /// Reinstall the bootloader (Limine, systemd-boot or GRUB, depending on what is installed) to
+    /// the ESP inside the chroot after the interactive session, the ESP has to be mounted
+    #[arg(long = "reinstall-bootloader", default_value_t = false)]
+    pub reinstall_bootloader: bool,
+
+    /// Keep retrying a mount that fails with a transient error (e.g. an I/O error while an
+    /// external disk spins up) for up to SECONDS before giving up, 0 disables retrying
+    #[arg(long = "mount-timeout", value_name = "SECONDS", default_value_t = 0)]
+    pub mount_timeout: u64,
+
+    /// Seconds to wait between mount retries with --mount-timeout
+    #[arg(long = "mount-retry-delay", value_name = "SECONDS", default_value_t = 2)]
+    pub mount_retry_delay: u64,
+
+    /// Abort when a partition from /etc/fstab fails to mount instead of skipping it
+    #[arg(long = "strict-fstab", default_value_t = false)]
+    pub strict_fstab: bool,
+
+    /// Print the raw JSON output of lsblk used to list partitions and exit, for attaching to bug
+    /// reports
+    #[arg(long = "debug-lsblk", default_value_t = false)]
+    pub debug_lsblk: bool,
+
+    /// Print which OS is installed on each Linux partition according to its os-release and exit,
+    /// partitions that are not mounted yet are briefly mounted read-only to read it. LUKS
+    /// encrypted partitions are not unlocked for this and skipped
+    #[arg(long = "detect-os", default_value_t = false)]
+    pub detect_os: bool,
+
+    /// Print the candidate devices, their BTRFS subvolumes and the installed OS as versioned JSON
+    /// without mounting anything for the chroot, Linux partitions are briefly mounted read-only to
+    /// list subvolumes and read os-release
+    #[arg(long = "enumerate-only", default_value_t = false)]
+    pub enumerate_only: bool,
 }
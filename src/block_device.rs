@@ -1,4 +1,7 @@
-use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub trait BlockOrSubvolumeID {
     fn get_id(&self) -> String;
@@ -7,23 +10,207 @@ pub trait BlockOrSubvolumeID {
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct BlockDevice {
     pub name: String,
-    #[serde(rename = "fstype")]
+    #[serde(rename = "fstype", default, deserialize_with = "deserialize_null_as_empty")]
     pub fs_type: String,
+    #[serde(default, deserialize_with = "deserialize_null_as_empty")]
     pub uuid: String,
     pub partuuid: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_label")]
     pub label: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_label")]
     pub partlabel: Option<String>,
+    pub parttype: Option<String>,
+    /// Size in bytes
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub size: Option<u64>,
+}
+
+/// lsblk prints missing values as null, older versions may leave rows without them despite the
+/// filter, those are dropped after parsing instead of failing the whole parse
+fn deserialize_null_as_empty<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Decodes the `\xNN` escapes lsblk uses for spaces and other special characters in labels, the
+/// bytes are decoded as UTF-8 together so multibyte characters survive
+fn unescape_lsblk(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i..i + 4)
+            .filter(|chunk| chunk.starts_with(b"\\x"))
+            .and_then(|chunk| std::str::from_utf8(&chunk[2..]).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 4;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn deserialize_label<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.map(|label| unescape_lsblk(&label)))
+}
+
+/// lsblk reports sizes in bytes as JSON numbers, but older versions print them as strings
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Number(u64),
+        Text(String),
+    }
+    Ok(match Option::<Size>::deserialize(deserializer)? {
+        Some(Size::Number(size)) => Some(size),
+        Some(Size::Text(size)) => size.parse().ok(),
+        None => None,
+    })
+}
+
+/// GPT partition type GUIDs from the Discoverable Partitions Specification and the mount points
+/// they are meant for
+const PARTITION_TYPE_MOUNT_POINTS: [(&str, &str); 6] = [
+    ("c12a7328-f81f-11d2-ba4b-00a0c93ec93b", "/boot/efi"),
+    ("bc13c2ff-59e6-4262-a352-b275fd6f7172", "/boot"),
+    ("933ac7e1-2eb4-4f13-b844-0e14e2aef915", "/home"),
+    ("3b8f8425-20e0-4f3b-907f-1a25a76f98e8", "/srv"),
+    ("4d21b016-b534-45c2-a9fb-5c16e091fd2d", "/var"),
+    ("7ec6f557-3bc5-4aca-b293-16ef5df639d1", "/var/tmp"),
+];
+
+/// Root partition type GUID from the Discoverable Partitions Specification for the architecture
+/// this is built for
+#[cfg(target_arch = "x86_64")]
+const ROOT_PARTITION_TYPE: Option<&str> = Some("4f68bce3-e8cd-4db1-96e7-fbcaf984b709");
+#[cfg(target_arch = "x86")]
+const ROOT_PARTITION_TYPE: Option<&str> = Some("44479540-f297-41b2-9af7-d131d5f0458a");
+#[cfg(target_arch = "aarch64")]
+const ROOT_PARTITION_TYPE: Option<&str> = Some("b921b045-1df0-41c3-af44-4c6f280d3fae");
+#[cfg(target_arch = "arm")]
+const ROOT_PARTITION_TYPE: Option<&str> = Some("69dad710-2ce4-4e3c-b16c-21a1d49abed3");
+#[cfg(target_arch = "riscv64")]
+const ROOT_PARTITION_TYPE: Option<&str> = Some("72ec70a6-cf74-40e6-bd49-4bda08e8f224");
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "riscv64"
+)))]
+const ROOT_PARTITION_TYPE: Option<&str> = None;
+
+impl BlockDevice {
+    pub fn has_known_fs_type(&self) -> bool {
+        !self.fs_type.trim().is_empty()
+    }
+
+    /// Matches a device spec as used on the command line or in fstab: a device path,
+    /// `UUID=`/`PARTUUID=`/`LABEL=`/`PARTLABEL=` or a bare UUID or label. For LUKS2 partitions the
+    /// label is the one set with `cryptsetup config --label`.
+    pub fn matches_spec(&self, spec: &str) -> bool {
+        match spec.split_once('=') {
+            Some(("UUID", uuid)) => self.uuid == uuid,
+            Some(("PARTUUID", partuuid)) => self.partuuid.as_deref() == Some(partuuid),
+            Some(("LABEL", label)) => self.label.as_deref() == Some(label),
+            Some(("PARTLABEL", partlabel)) => self.partlabel.as_deref() == Some(partlabel),
+            _ => {
+                self.name == spec
+                    || self.uuid == spec
+                    || self.label.as_deref() == Some(spec)
+                    || self.partlabel.as_deref() == Some(spec)
+            },
+        }
+    }
+
+    /// Whether the GPT partition type marks this as the root partition for this architecture
+    pub fn is_discoverable_root(&self) -> bool {
+        match (ROOT_PARTITION_TYPE, &self.parttype) {
+            (Some(root_type), Some(parttype)) => root_type.eq_ignore_ascii_case(parttype),
+            _ => false,
+        }
+    }
+
+    pub fn suggested_mount_point(&self) -> Option<&'static str> {
+        let parttype = self.parttype.as_ref()?;
+        PARTITION_TYPE_MOUNT_POINTS
+            .iter()
+            .find(|(guid, _)| guid.eq_ignore_ascii_case(parttype))
+            .map(|(_, mount_point)| *mount_point)
+    }
+}
+
+/// Splits a device path into text and number chunks, so `/dev/sda2` sorts before `/dev/sda10`
+fn device_name_chunks(name: &str) -> Vec<(String, u64)> {
+    let mut chunks: Vec<(String, u64)> = Vec::new();
+    let mut text = String::new();
+    let mut digits = String::new();
+    for c in name.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        if !digits.is_empty() {
+            chunks.push((std::mem::take(&mut text), digits.parse().unwrap_or(u64::MAX)));
+            digits.clear();
+        }
+        text.push(c);
+    }
+    let number = if digits.is_empty() { 0 } else { digits.parse().unwrap_or(u64::MAX) };
+    chunks.push((text, number));
+    chunks
+}
+
+/// Orders devices by path with numbers compared numerically, which keeps partitions grouped
+/// under their parent disk in a stable order
+pub fn compare_device_names(a: &str, b: &str) -> Ordering {
+    device_name_chunks(a).cmp(&device_name_chunks(b))
 }
 
 impl std::fmt::Display for BlockDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Partition: {}: FS: {} UUID: {}", self.name, self.fs_type, self.uuid)
+        write!(f, "Partition: {}: FS: {} UUID: {}", self.name, self.fs_type, self.uuid)?;
+        // Shown so encrypted partitions can be told apart (and searched for) by their label
+        if let Some(label) = &self.label {
+            write!(f, " Label: {}", label)?;
+        }
+        Ok(())
     }
 }
 
 impl BlockOrSubvolumeID for BlockDevice {
+    /// Partitions without a filesystem have no UUID, their PARTUUID identifies them instead
     fn get_id(&self) -> String {
-        self.uuid.clone()
+        match &self.partuuid {
+            Some(partuuid) if self.uuid.is_empty() => partuuid.clone(),
+            _ => self.uuid.clone(),
+        }
+    }
+}
+
+/// Space usage of a BTRFS filesystem as printed by `btrfs filesystem usage`, e.g. `931.51GiB`
+#[derive(Clone)]
+pub struct BTRFSUsage {
+    pub total: String,
+    pub used: String,
+    pub free: String,
+}
+
+impl std::fmt::Display for BTRFSUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} total, {} used, {} free", self.total, self.used, self.free)
     }
 }
 
@@ -31,12 +218,19 @@ impl BlockOrSubvolumeID for BlockDevice {
 pub struct BTRFSSubVolume {
     pub device: BlockDevice,
     pub subvolume_id: usize,
+    /// ID of the subvolume containing this one, 0 for the top level itself
+    pub parent_id: usize,
     pub subvolume_name: String,
 }
 
 impl BTRFSSubVolume {
-    pub fn new(device: BlockDevice, subvolume_id: usize, subvolume_name: String) -> Self {
-        BTRFSSubVolume { device, subvolume_id, subvolume_name }
+    pub fn new(
+        device: BlockDevice,
+        subvolume_id: usize,
+        parent_id: usize,
+        subvolume_name: String,
+    ) -> Self {
+        BTRFSSubVolume { device, subvolume_id, parent_id, subvolume_name }
     }
 }
 
@@ -56,8 +250,101 @@ impl BlockOrSubvolumeID for BTRFSSubVolume {
     }
 }
 
+/// Results of probing devices, keyed by device UUID, so each device is probed at most once per run
+#[derive(Default)]
+pub struct DiscoveryCache {
+    pub btrfs_subvolumes: HashMap<String, Vec<BTRFSSubVolume>>,
+    pub btrfs_usage: HashMap<String, BTRFSUsage>,
+    pub unlocked_luks_devices: HashSet<String>,
+}
+
+/// A row of lsblk's JSON output, depending on the version and flags partitions are nested under
+/// their disk and mapped devices under their partition in `children`
+#[derive(Serialize, Deserialize)]
+pub struct LsblkEntry {
+    #[serde(flatten)]
+    pub device: BlockDevice,
+    #[serde(default)]
+    pub children: Vec<LsblkEntry>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct BlockDevices {
     #[serde(rename = "blockdevices")]
-    pub block_devices: Vec<BlockDevice>,
+    pub block_devices: Vec<LsblkEntry>,
+}
+
+impl BlockDevices {
+    /// Flattens the tree of devices, containers without a filesystem of their own (e.g. a disk
+    /// holding partitions) are left out while a LUKS partition is kept next to its mapping
+    pub fn into_flat(self) -> Vec<BlockDevice> {
+        fn flatten(entry: LsblkEntry, devices: &mut Vec<BlockDevice>) {
+            if entry.children.is_empty() || !entry.device.fs_type.is_empty() {
+                devices.push(entry.device);
+            }
+            for child in entry.children {
+                flatten(child, devices);
+            }
+        }
+        let mut devices = Vec::new();
+        for entry in self.block_devices {
+            flatten(entry, &mut devices);
+        }
+        devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NESTED_LSBLK_JSON: &str = r#"{
+   "blockdevices": [
+      {"name":"/dev/sda", "fstype":null, "uuid":null, "partuuid":null, "label":null,
+       "partlabel":null, "parttype":null, "size":512110190592,
+         "children": [
+            {"name":"/dev/sda1", "fstype":"vfat", "uuid":"1234-ABCD", "partuuid":"p1",
+             "label":null, "partlabel":"EFI", "parttype":"c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
+             "size":"536870912"},
+            {"name":"/dev/sda2", "fstype":"crypto_LUKS", "uuid":"luks-uuid", "partuuid":"p2",
+             "label":null, "partlabel":null, "parttype":null, "size":511572271104,
+               "children": [
+                  {"name":"/dev/mapper/luks-luks-uuid", "fstype":"btrfs", "uuid":"btrfs-uuid",
+                   "partuuid":null, "label":null, "partlabel":null, "parttype":null,
+                   "size":511555493888}
+               ]
+            }
+         ]
+      },
+      {"name":"/dev/sdb1", "fstype":"ext4", "uuid":"ext4-uuid", "partuuid":null, "label":"data",
+       "partlabel":null, "parttype":null, "size":1000}
+   ]
+}"#;
+
+    #[test]
+    fn escaped_labels_are_decoded() {
+        let device: BlockDevice = serde_json::from_str(
+            r#"{"name":"/dev/sdb1", "fstype":"ext4", "uuid":"ext4-uuid", "partuuid":null,
+                "label":"My\\x20Data", "partlabel":"caf\\xc3\\xa9", "parttype":null, "size":null}"#,
+        )
+        .unwrap();
+        assert_eq!(device.label.as_deref(), Some("My Data"));
+        assert_eq!(device.partlabel.as_deref(), Some("café"));
+        assert!(device.matches_spec("LABEL=My Data"));
+    }
+
+    #[test]
+    fn nested_lsblk_output_is_flattened() {
+        let devices: BlockDevices = serde_json::from_str(NESTED_LSBLK_JSON).unwrap();
+        let devices = devices.into_flat();
+        let names = devices.iter().map(|device| device.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec![
+            "/dev/sda1",
+            "/dev/sda2",
+            "/dev/mapper/luks-luks-uuid",
+            "/dev/sdb1"
+        ]);
+        assert_eq!(devices[0].size, Some(536870912));
+        assert_eq!(devices[2].fs_type, "btrfs");
+    }
 }
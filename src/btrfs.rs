@@ -0,0 +1,463 @@
+use crate::block_device::{BTRFSSubVolume, BTRFSUsage, BlockDevice, DiscoveryCache};
+use crate::error::ChrootError;
+use crate::mount::{
+    create_temp_mount_point, mount_block_device, normalize_mount_options, umount_block_device,
+};
+use crate::user_input;
+
+use std::path::Path;
+
+use nix::unistd::Uid;
+use subprocess::{Exec, Redirection};
+
+pub const TOP_LEVEL_SUBVOLUME_ID: usize = 5;
+
+/// Subvolume requested by the `subvolid=`/`subvol=` mount options of an fstab entry
+#[derive(Debug, PartialEq)]
+pub enum SubvolSelector {
+    Id(usize),
+    Name(String),
+    Unspecified,
+}
+
+impl SubvolSelector {
+    pub fn find<'a>(&self, subvolumes: &'a [BTRFSSubVolume]) -> Option<&'a BTRFSSubVolume> {
+        match self {
+            SubvolSelector::Id(id) => subvolumes.iter().find(|subvol| subvol.subvolume_id == *id),
+            SubvolSelector::Name(name) => {
+                subvolumes.iter().find(|subvol| subvol.subvolume_name == *name)
+            },
+            SubvolSelector::Unspecified => subvolumes.first(),
+        }
+    }
+}
+
+/// `subvolid=` takes precedence over `subvol=`, as it does for mount. Subvolume names are compared
+/// without their leading slash, since that is how `btrfs subvolume list` prints them.
+pub fn parse_btrfs_subvol_option(options: &[String]) -> SubvolSelector {
+    let options = normalize_mount_options(options);
+    let subvolume_id =
+        options.iter().find_map(|opt| opt.strip_prefix("subvolid=").and_then(|id| id.parse().ok()));
+    if let Some(subvolume_id) = subvolume_id {
+        return SubvolSelector::Id(subvolume_id);
+    }
+    let subvolume_name = options.iter().find_map(|opt| opt.strip_prefix("subvol="));
+    match subvolume_name.map(|name| name.trim_start_matches('/')) {
+        Some("") => SubvolSelector::Id(TOP_LEVEL_SUBVOLUME_ID),
+        Some(name) => SubvolSelector::Name(name.to_owned()),
+        None => SubvolSelector::Unspecified,
+    }
+}
+
+pub fn list_subvolumes(
+    device: &BlockDevice,
+    include_dot_snapshots: bool,
+) -> Result<Vec<BTRFSSubVolume>, ChrootError> {
+    probe_subvolumes(device, include_dot_snapshots).map(|(subvolumes, _)| subvolumes)
+}
+
+/// Summarizes `btrfs filesystem usage` of the filesystem mounted at the mount point
+fn read_filesystem_usage(mount_point: &str) -> Option<BTRFSUsage> {
+    let capture = Exec::cmd("btrfs")
+        .args(&["filesystem", "usage", mount_point])
+        .stderr(subprocess::NullFile)
+        .capture()
+        .ok()
+        .filter(|capture| capture.success())?;
+    let output = capture.stdout_str();
+    let value = |key: &str| {
+        output.lines().find_map(|line| {
+            let (name, value) = line.trim().split_once(':')?;
+            if name != key {
+                return None;
+            }
+            value.split_whitespace().next().map(str::to_owned)
+        })
+    };
+    Some(BTRFSUsage {
+        total: value("Device size")?,
+        used: value("Used")?,
+        free: value("Free (estimated)")?,
+    })
+}
+
+/// Options mounting exactly the given subvolume, regardless of the filesystem's default subvolume
+/// (e.g. set by `btrfs subvolume set-default`)
+fn subvolume_mount_options(subvolume_id: usize, read_only: bool) -> Vec<String> {
+    let mut options = vec![format!("subvolid={}", subvolume_id)];
+    if read_only {
+        options.insert(0, "ro".to_owned());
+    }
+    options
+}
+
+/// Lists the subvolumes of the device and reads its space usage while it is mounted anyway
+fn probe_subvolumes(
+    device: &BlockDevice,
+    include_dot_snapshots: bool,
+) -> Result<(Vec<BTRFSSubVolume>, Option<BTRFSUsage>), ChrootError> {
+    let mount_point =
+        create_temp_mount_point(&format!("cachyos-chroot-temp-mount-{}-", &device.uuid))?;
+    let mount_point = mount_point.as_str();
+
+    // The probe mounts the top level explicitly, with the default subvolume the listing would be
+    // relative to that subvolume and the synthetic top-level entry would point at the wrong tree
+    let options = subvolume_mount_options(TOP_LEVEL_SUBVOLUME_ID, true);
+    if let Err(err) = mount_block_device(device, mount_point, Some(options)) {
+        if !Uid::effective().is_root() {
+            return Err(ChrootError::SubvolumeList {
+                device: device.name.clone(),
+                requires_root: true,
+            });
+        }
+        return Err(err);
+    }
+
+    let result = Exec::cmd("btrfs")
+        .args(&["subvolume", "list", "-t", mount_point])
+        .stderr(Redirection::Pipe)
+        .capture();
+    let subvolumes_raw = match result {
+        Ok(capture) if capture.success() => capture.stdout_str(),
+        result => {
+            umount_block_device(mount_point, false);
+            let permission_denied = result.is_ok_and(|capture| {
+                let stderr = capture.stderr_str();
+                stderr.contains("Operation not permitted") || stderr.contains("Permission denied")
+            });
+            return Err(ChrootError::SubvolumeList {
+                device: device.name.clone(),
+                requires_root: permission_denied || !Uid::effective().is_root(),
+            });
+        },
+    };
+    let subvolumes = parse_subvolume_list(device, &subvolumes_raw, include_dot_snapshots);
+
+    let usage = read_filesystem_usage(mount_point);
+    umount_block_device(mount_point, false);
+
+    Ok((subvolumes, usage))
+}
+
+/// Parses the table printed by `btrfs subvolume list -t`, with the top level prepended since it is
+/// not listed
+fn parse_subvolume_list(
+    device: &BlockDevice,
+    output: &str,
+    include_dot_snapshots: bool,
+) -> Vec<BTRFSSubVolume> {
+    let mut subvolumes =
+        vec![BTRFSSubVolume::new(device.clone(), TOP_LEVEL_SUBVOLUME_ID, 0, "/".to_owned())];
+
+    // The first two lines are the table header, the columns are ID, generation, top level (the
+    // parent subvolume ID) and path
+    for subvolume in output.trim().lines().skip(2) {
+        let subvolume_parts = subvolume.split_whitespace().collect::<Vec<_>>();
+
+        if subvolume_parts.len() == 4 {
+            let (Ok(subvolume_id), Ok(parent_id)) =
+                (subvolume_parts[0].parse(), subvolume_parts[2].parse())
+            else {
+                log::warn!("Unexpected line in BTRFS subvolume list: {}", subvolume);
+                continue;
+            };
+            let subvolume_name = subvolume_parts[3];
+            if subvolume_name.starts_with(".snapshots") && !include_dot_snapshots {
+                continue;
+            }
+            subvolumes.push(BTRFSSubVolume::new(
+                device.clone(),
+                subvolume_id,
+                parent_id,
+                subvolume_name.to_string(),
+            ));
+        }
+    }
+    subvolumes
+}
+
+/// Snapper keeps its snapshots at `.snapshots/<number>/snapshot`
+pub fn is_snapper_snapshot(subvolume: &BTRFSSubVolume) -> bool {
+    let mut components = subvolume.subvolume_name.rsplit('/');
+    components.next() == Some("snapshot")
+        && components.next().is_some_and(|number| number.parse::<u64>().is_ok())
+        && components.next() == Some(".snapshots")
+}
+
+/// Reads the `ro` property of the subvolume, which requires mounting it temporarily
+pub fn is_read_only_subvolume(subvolume: &BTRFSSubVolume) -> Result<bool, ChrootError> {
+    let device = &subvolume.device;
+    let mount_point =
+        create_temp_mount_point(&format!("cachyos-chroot-temp-mount-{}-", &device.uuid))?;
+    let mount_point = mount_point.as_str();
+
+    mount_block_device(
+        device,
+        mount_point,
+        Some(subvolume_mount_options(subvolume.subvolume_id, true)),
+    )?;
+    let result = Exec::cmd("btrfs").args(&["property", "get", "-ts", mount_point, "ro"]).capture();
+    umount_block_device(mount_point, false);
+
+    match result {
+        Ok(capture) if capture.success() => Ok(capture.stdout_str().trim() == "ro=true"),
+        _ => Err(ChrootError::SubvolumeProperty { subvolume: subvolume.subvolume_name.clone() }),
+    }
+}
+
+/// Creates a writable snapshot of a read-only snapshot at the top level of the filesystem, named
+/// after the Snapper snapshot number (e.g. `@snapshot-12-writable`), and returns it
+pub fn create_writable_snapshot(subvolume: &BTRFSSubVolume) -> Result<BTRFSSubVolume, ChrootError> {
+    let device = &subvolume.device;
+    let error = || ChrootError::SubvolumeSnapshot { subvolume: subvolume.subvolume_name.clone() };
+    let number = subvolume.subvolume_name.rsplit('/').nth(1).unwrap_or("0");
+    let target_name = format!("@snapshot-{}-writable", number);
+
+    let mount_point =
+        create_temp_mount_point(&format!("cachyos-chroot-temp-mount-{}-", &device.uuid))?;
+    let mount_point = mount_point.as_str();
+    mount_block_device(
+        device,
+        mount_point,
+        Some(subvolume_mount_options(TOP_LEVEL_SUBVOLUME_ID, false)),
+    )?;
+
+    let source = Path::new(mount_point).join(&subvolume.subvolume_name);
+    let target = Path::new(mount_point).join(&target_name);
+    if target.exists() {
+        umount_block_device(mount_point, false);
+        log::error!("Subvolume {} already exists", target_name);
+        return Err(error());
+    }
+    log::info!("Creating writable snapshot {} of {}", target_name, subvolume.subvolume_name);
+    let snapshot =
+        Exec::cmd("btrfs").arg("subvolume").arg("snapshot").arg(&source).arg(&target).join();
+    let subvolume_id = match snapshot {
+        Ok(status) if status.success() => Exec::cmd("btrfs")
+            .args(&["inspect-internal", "rootid"])
+            .arg(&target)
+            .capture()
+            .ok()
+            .and_then(|capture| capture.stdout_str().trim().parse::<usize>().ok()),
+        _ => None,
+    };
+    umount_block_device(mount_point, false);
+
+    match subvolume_id {
+        Some(subvolume_id) => Ok(BTRFSSubVolume::new(
+            device.clone(),
+            subvolume_id,
+            TOP_LEVEL_SUBVOLUME_ID,
+            target_name,
+        )),
+        None => Err(error()),
+    }
+}
+
+pub fn get_known_subvolumes(
+    device: &BlockDevice,
+    cache: &mut DiscoveryCache,
+    show_btrfs_dot_snapshots: bool,
+) -> Result<Vec<BTRFSSubVolume>, ChrootError> {
+    if let Some(subvolumes) = cache.btrfs_subvolumes.get(&device.uuid) {
+        return Ok(subvolumes.clone());
+    }
+    let (subvolumes, usage) = probe_subvolumes(device, show_btrfs_dot_snapshots)?;
+    let count =
+        subvolumes.iter().filter(|subvol| subvol.subvolume_id != TOP_LEVEL_SUBVOLUME_ID).count();
+    log::info!("Found {} BTRFS subvolumes on {}", count, device.name);
+    cache.btrfs_subvolumes.insert(device.uuid.clone(), subvolumes.clone());
+    if let Some(usage) = usage {
+        cache.btrfs_usage.insert(device.uuid.clone(), usage);
+    }
+    Ok(subvolumes)
+}
+
+/// Finds the `@` subvolume the CachyOS BTRFS preset uses as root. Only an `@` directly below the
+/// top level counts, a nested one (e.g. inside a snapshot) is left for the user to pick.
+fn preset_root_subvolume(subvolumes: &[BTRFSSubVolume]) -> Option<&BTRFSSubVolume> {
+    subvolumes
+        .iter()
+        .find(|subvol| subvol.parent_id == TOP_LEVEL_SUBVOLUME_ID && subvol.subvolume_name == "@")
+}
+
+pub fn get_btrfs_subvolume(
+    device: &BlockDevice,
+    cache: &mut DiscoveryCache,
+    show_btrfs_dot_snapshots: bool,
+    device_name: &str,
+) -> Result<BTRFSSubVolume, ChrootError> {
+    let known_subvolumes = get_known_subvolumes(device, cache, show_btrfs_dot_snapshots)?;
+    if known_subvolumes.len() == 1 {
+        log::warn!("No subvolumes found, using root subvolume");
+        return Ok(known_subvolumes[0].clone());
+    }
+    if device_name == "root" {
+        if let Some(subvolume) = preset_root_subvolume(&known_subvolumes) {
+            if user_input::use_cachyos_btrfs_preset() {
+                return Ok(subvolume.clone());
+            }
+        }
+    }
+    let usage = cache.btrfs_usage.get(&device.uuid);
+    user_input::get_btrfs_subvolume(device_name, &known_subvolumes, usage)
+        .ok_or_else(|| ChrootError::SubvolumeNotSelected { device: device.name.clone() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(opts: &[&str]) -> Vec<String> {
+        opts.iter().map(|opt| opt.to_string()).collect()
+    }
+
+    fn device() -> BlockDevice {
+        BlockDevice {
+            name: "/dev/sda2".to_owned(),
+            fs_type: "btrfs".to_owned(),
+            uuid: "abcd".to_owned(),
+            partuuid: None,
+            label: None,
+            partlabel: None,
+            parttype: None,
+            size: None,
+        }
+    }
+
+    fn subvolume(name: &str) -> BTRFSSubVolume {
+        BTRFSSubVolume::new(device(), 300, TOP_LEVEL_SUBVOLUME_ID, name.to_owned())
+    }
+
+    const PRESET_SUBVOLUME_LIST: &str = "\
+ID	gen	top level	path	
+--	---	---------	----	
+256	1054	5		@
+257	1054	5		@home
+258	1050	5		@root
+259	1012	5		@srv
+260	1054	5		@cache
+261	1054	5		@tmp
+262	1054	5		@log
+263	20	256		var/lib/portables
+264	20	256		var/lib/machines
+";
+
+    // A snapshot of the whole top level, so its nested subvolumes have non-top-level parents
+    const NESTED_SUBVOLUME_LIST: &str = "\
+ID	gen	top level	path	
+--	---	---------	----	
+256	88	5		backup
+257	88	256		backup/@
+258	88	256		backup/@home
+";
+
+    #[test]
+    fn detects_snapper_snapshots() {
+        assert!(is_snapper_snapshot(&subvolume(".snapshots/12/snapshot")));
+        assert!(is_snapper_snapshot(&subvolume("@/.snapshots/1/snapshot")));
+        assert!(!is_snapper_snapshot(&subvolume("@snapshots")));
+        assert!(!is_snapper_snapshot(&subvolume(".snapshots/latest/snapshot")));
+        assert!(!is_snapper_snapshot(&subvolume("@home")));
+    }
+
+    #[test]
+    fn subvolume_mount_options_select_the_subvolume() {
+        let probe_options = subvolume_mount_options(TOP_LEVEL_SUBVOLUME_ID, true);
+        assert!(probe_options.contains(&"ro".to_owned()));
+        assert_eq!(
+            parse_btrfs_subvol_option(&probe_options),
+            SubvolSelector::Id(TOP_LEVEL_SUBVOLUME_ID)
+        );
+        let options = subvolume_mount_options(256, false);
+        assert!(!options.contains(&"ro".to_owned()));
+        assert_eq!(parse_btrfs_subvol_option(&options), SubvolSelector::Id(256));
+    }
+
+    #[test]
+    fn parses_subvolume_list() {
+        let subvolumes = parse_subvolume_list(&device(), PRESET_SUBVOLUME_LIST, false);
+        assert_eq!(subvolumes.len(), 10);
+        assert_eq!(subvolumes[0].subvolume_id, TOP_LEVEL_SUBVOLUME_ID);
+        let portables = &subvolumes[8];
+        assert_eq!(portables.subvolume_name, "var/lib/portables");
+        assert_eq!((portables.subvolume_id, portables.parent_id), (263, 256));
+    }
+
+    #[test]
+    fn preset_root_subvolume_is_top_level_at() {
+        let subvolumes = parse_subvolume_list(&device(), PRESET_SUBVOLUME_LIST, false);
+        let preset = preset_root_subvolume(&subvolumes).unwrap();
+        assert_eq!((preset.subvolume_id, preset.subvolume_name.as_str()), (256, "@"));
+    }
+
+    #[test]
+    fn preset_root_subvolume_ignores_nested_at() {
+        let subvolumes = parse_subvolume_list(&device(), NESTED_SUBVOLUME_LIST, false);
+        assert!(preset_root_subvolume(&subvolumes).is_none());
+    }
+
+    #[test]
+    fn parses_subvolid() {
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&["rw", "subvolid=256"])),
+            SubvolSelector::Id(256)
+        );
+    }
+
+    #[test]
+    fn parses_subvol_name() {
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&["subvol=@home", "noatime"])),
+            SubvolSelector::Name("@home".to_owned())
+        );
+    }
+
+    #[test]
+    fn strips_leading_slash_from_subvol_name() {
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&["subvol=/@home"])),
+            SubvolSelector::Name("@home".to_owned())
+        );
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&["subvol=/"])),
+            SubvolSelector::Id(TOP_LEVEL_SUBVOLUME_ID)
+        );
+    }
+
+    #[test]
+    fn prefers_subvolid_when_both_present() {
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&["subvol=/@home", "subvolid=257"])),
+            SubvolSelector::Id(257)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_subvol_on_invalid_subvolid() {
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&["subvolid=abc", "subvol=@"])),
+            SubvolSelector::Name("@".to_owned())
+        );
+    }
+
+    #[test]
+    fn parses_options_with_whitespace_and_commas() {
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&[" noatime ", "rw, subvolid=256"])),
+            SubvolSelector::Id(256)
+        );
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&["compress=zstd,\tsubvol=/@home,"])),
+            SubvolSelector::Name("@home".to_owned())
+        );
+    }
+
+    #[test]
+    fn handles_neither_present() {
+        assert_eq!(
+            parse_btrfs_subvol_option(&options(&["rw", "compress=zstd"])),
+            SubvolSelector::Unspecified
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use which::which;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Feature {
+    Core,
+    Btrfs,
+    Luks,
+    Image,
+    Genfstab,
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Feature::Core => write!(f, "Core"),
+            Feature::Btrfs => write!(f, "BTRFS"),
+            Feature::Luks => write!(f, "LUKS"),
+            Feature::Image => write!(f, "Images"),
+            Feature::Genfstab => write!(f, "genfstab"),
+        }
+    }
+}
+
+/// Features that can be used during this run, a feature can be turned off even if its commands are
+/// installed
+#[derive(Clone)]
+pub struct Features {
+    pub btrfs: bool,
+    pub luks: bool,
+    pub image: bool,
+    pub genfstab: bool,
+    /// Filesystem types left out of the partition lists with --exclude-fstype
+    pub excluded_fs_types: Vec<String>,
+}
+
+impl Features {
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::Core => true,
+            Feature::Btrfs => self.btrfs,
+            Feature::Luks => self.luks,
+            Feature::Image => self.image,
+            Feature::Genfstab => self.genfstab,
+        }
+    }
+
+    /// Devices with a filesystem of a disabled feature are treated as unsupported
+    pub fn supports_fs_type(&self, fs_type: &str) -> bool {
+        match fs_type {
+            "btrfs" => self.btrfs,
+            "crypto_LUKS" => self.luks,
+            _ => true,
+        }
+    }
+
+    /// Compared case-insensitively, lsblk reports e.g. `crypto_LUKS` while users tend to type it
+    /// in lowercase
+    pub fn is_excluded_fs_type(&self, fs_type: &str) -> bool {
+        self.excluded_fs_types.iter().any(|excluded| excluded.eq_ignore_ascii_case(fs_type))
+    }
+}
+
+pub const FEATURES: [Feature; 5] =
+    [Feature::Core, Feature::Btrfs, Feature::Luks, Feature::Image, Feature::Genfstab];
+
+/// Commands used by each feature and the package providing them
+pub const DEPENDS: [(&str, &str, Feature); 9] = [
+    ("lsblk", "util-linux", Feature::Core),
+    ("mount", "util-linux", Feature::Core),
+    ("umount", "util-linux", Feature::Core),
+    ("findmnt", "util-linux", Feature::Core),
+    ("arch-chroot", "arch-install-scripts", Feature::Core),
+    ("genfstab", "arch-install-scripts", Feature::Genfstab),
+    ("btrfs", "btrfs-progs", Feature::Btrfs),
+    ("cryptsetup", "cryptsetup", Feature::Luks),
+    ("losetup", "util-linux", Feature::Image),
+];
+
+/// Returns the missing commands of a feature along with the packages providing them
+pub fn missing_commands(feature: Feature) -> Vec<(&'static str, &'static str)> {
+    DEPENDS
+        .iter()
+        .filter(|(cmd, _, dep_feature)| *dep_feature == feature && which(cmd).is_err())
+        .map(|(cmd, pkg, _)| (*cmd, *pkg))
+        .collect()
+}
+
+/// One line summary of which features can be used on this system
+pub fn features_summary() -> String {
+    FEATURES
+        .iter()
+        .map(|feature| {
+            let mut packages =
+                missing_commands(*feature).into_iter().map(|(_, pkg)| pkg).collect::<Vec<_>>();
+            packages.dedup();
+            if packages.is_empty() {
+                format!("{}: available", feature)
+            } else {
+                format!("{}: unavailable (install {})", feature, packages.join(", "))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,72 @@
+use crate::block_device::BlockDevice;
+use crate::btrfs;
+use crate::os_release::{self, OsRelease};
+
+use serde::Serialize;
+
+/// Version of the `--enumerate-only` output, bumped whenever the fields change so consumers like
+/// the installer can tell output they don't understand apart. Version 2 added `os`.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Serialize)]
+pub struct Enumeration {
+    pub schema_version: u32,
+    pub devices: Vec<EnumeratedDevice>,
+}
+
+#[derive(Serialize)]
+pub struct EnumeratedDevice {
+    #[serde(flatten)]
+    pub device: BlockDevice,
+    /// Only present for BTRFS partitions whose subvolumes could be listed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subvolumes: Option<Vec<EnumeratedSubvolume>>,
+    /// Only present for Linux partitions with an os-release file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<OsRelease>,
+}
+
+#[derive(Serialize)]
+pub struct EnumeratedSubvolume {
+    pub id: usize,
+    pub name: String,
+}
+
+/// Collects the candidate devices together with their BTRFS subvolumes and installed OS, which
+/// requires a read-only probe mount of every Linux partition
+pub fn enumerate(block_devices: &[BlockDevice], include_dot_snapshots: bool) -> Enumeration {
+    let devices = block_devices
+        .iter()
+        .map(|device| {
+            let subvolumes = if device.fs_type == "btrfs" {
+                match btrfs::list_subvolumes(device, include_dot_snapshots) {
+                    Ok(subvolumes) => Some(
+                        subvolumes
+                            .into_iter()
+                            .map(|subvolume| EnumeratedSubvolume {
+                                id: subvolume.subvolume_id,
+                                name: subvolume.subvolume_name,
+                            })
+                            .collect(),
+                    ),
+                    Err(err) => {
+                        log::error!("{}", err);
+                        None
+                    },
+                }
+            } else {
+                None
+            };
+            let os = if os_release::LINUX_ROOT_FS_TYPES.contains(&device.fs_type.as_str()) {
+                crate::detect_os(device).unwrap_or_else(|err| {
+                    log::error!("{}", err);
+                    None
+                })
+            } else {
+                None
+            };
+            EnumeratedDevice { device: device.clone(), subvolumes, os }
+        })
+        .collect();
+    Enumeration { schema_version: SCHEMA_VERSION, devices }
+}
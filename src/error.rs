@@ -0,0 +1,57 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ChrootError {
+    Mount { device: String, mount_point: String },
+    Remount { mount_point: String },
+    LuksOpen { device: String },
+    Genfstab { mount_point: String },
+    SubvolumeList { device: String, requires_root: bool },
+    SubvolumeProperty { subvolume: String },
+    SubvolumeSnapshot { subvolume: String },
+    SubvolumeNotSelected { device: String },
+    LoopSetup { image: String },
+    Lsblk { reason: String },
+    TempDir,
+}
+
+impl fmt::Display for ChrootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChrootError::Mount { device, mount_point } => {
+                write!(f, "Failed to mount partition {} at {}", device, mount_point)
+            },
+            ChrootError::Remount { mount_point } => {
+                write!(f, "Failed to remount partition at {}", mount_point)
+            },
+            ChrootError::LuksOpen { device } => {
+                write!(f, "Failed to open LUKS encrypted partition {}", device)
+            },
+            ChrootError::Genfstab { mount_point } => {
+                write!(f, "Failed to generate fstab for {}", mount_point)
+            },
+            ChrootError::SubvolumeList { device, requires_root: true } => {
+                write!(f, "BTRFS subvolume listing of {} requires root", device)
+            },
+            ChrootError::SubvolumeList { device, requires_root: false } => {
+                write!(f, "Failed to list BTRFS subvolumes of {}", device)
+            },
+            ChrootError::SubvolumeProperty { subvolume } => {
+                write!(f, "Failed to read the properties of BTRFS subvolume {}", subvolume)
+            },
+            ChrootError::SubvolumeSnapshot { subvolume } => {
+                write!(f, "Failed to create a writable snapshot of BTRFS subvolume {}", subvolume)
+            },
+            ChrootError::SubvolumeNotSelected { device } => {
+                write!(f, "No BTRFS subvolume was selected for {}", device)
+            },
+            ChrootError::LoopSetup { image } => {
+                write!(f, "Failed to attach image {} to a loop device", image)
+            },
+            ChrootError::Lsblk { reason } => write!(f, "Failed to list block devices, {}", reason),
+            ChrootError::TempDir => write!(f, "Failed to create a temporary directory"),
+        }
+    }
+}
+
+impl std::error::Error for ChrootError {}
@@ -0,0 +1,75 @@
+use crate::error::ChrootError;
+
+use std::fs;
+use std::path::Path;
+
+use subprocess::Exec;
+use which::which;
+
+/// A disk image attached to a loop device, `kpartx` is set when its partitions were mapped with
+/// kpartx instead of the partition scanning of losetup
+pub struct AttachedImage {
+    pub loop_device: String,
+    pub kpartx: bool,
+}
+
+/// Checks for the `/dev/loopNpM` nodes created by losetup's partition scanning
+fn has_partition_nodes(loop_device: &str) -> bool {
+    let Some(loop_name) = Path::new(loop_device).file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let prefix = format!("{}p", loop_name);
+    fs::read_dir("/dev")
+        .map(|entries| {
+            entries.flatten().any(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        })
+        .unwrap_or(false)
+}
+
+/// Attaches a disk image to a free loop device so the partitions inside it show up, either as
+/// `/dev/loopNpM` through losetup or as `/dev/mapper/loopNpM` through kpartx on systems where
+/// losetup's partition scanning doesn't work
+pub fn attach_image(image_path: &str) -> Result<AttachedImage, ChrootError> {
+    log::info!("Attaching image {} to a loop device", image_path);
+    let result = Exec::cmd("losetup").args(&["-f", "-P", "--show", image_path]).capture();
+    let loop_device = match result {
+        Ok(capture) if capture.success() => capture.stdout_str().trim().to_owned(),
+        _ => return Err(ChrootError::LoopSetup { image: image_path.to_owned() }),
+    };
+    log::info!("Attached image {} to {}", image_path, loop_device);
+
+    if has_partition_nodes(&loop_device) {
+        log::info!("Using losetup partition scanning for the partitions of {}", loop_device);
+        return Ok(AttachedImage { loop_device, kpartx: false });
+    }
+    if which("kpartx").is_err() {
+        log::warn!(
+            "losetup found no partitions in {} and kpartx is not installed, install \
+             multipath-tools if the image is partitioned",
+            image_path
+        );
+        return Ok(AttachedImage { loop_device, kpartx: false });
+    }
+    log::info!("losetup found no partitions in {}, falling back to kpartx", image_path);
+    let result = Exec::cmd("kpartx").args(&["-a", "-v", &loop_device]).join();
+    if result.is_err() || !result.unwrap().success() {
+        log::warn!("kpartx failed to map the partitions of {}", loop_device);
+    }
+    Ok(AttachedImage { loop_device, kpartx: true })
+}
+
+pub fn detach_image(image: &AttachedImage) -> bool {
+    if image.kpartx {
+        log::info!("Removing kpartx mappings of {}", image.loop_device);
+        let result = Exec::cmd("kpartx").args(&["-d", &image.loop_device]).join();
+        if result.is_err() || !result.unwrap().success() {
+            log::warn!("Failed to remove kpartx mappings of {}", image.loop_device);
+        }
+    }
+    log::info!("Detaching loop device {}", image.loop_device);
+    let result = Exec::cmd("losetup").args(&["-d", &image.loop_device]).join();
+    if result.is_err() || !result.unwrap().success() {
+        log::warn!("Failed to detach loop device {}", image.loop_device);
+    }
+    true
+}
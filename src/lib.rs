@@ -0,0 +1,843 @@
+pub mod block_device;
+pub mod btrfs;
+pub mod depends;
+pub mod enumerate;
+pub mod error;
+pub mod image;
+pub mod logger;
+pub mod luks;
+pub mod mount;
+pub mod os_release;
+pub mod progress;
+pub mod repair;
+pub mod user_input;
+pub mod utils;
+pub mod xfs;
+
+use block_device::{BlockDevice, BlockOrSubvolumeID, DiscoveryCache};
+use btrfs::SubvolSelector;
+use error::ChrootError;
+use mount::{mount_block_device, remount_block_device, MountRegistry};
+use os_release::OsRelease;
+use progress::ProgressEvent;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use colored::Colorize;
+use fstab::{FsEntry, FsTab};
+use subprocess::Exec;
+
+/// Returns the raw JSON lsblk prints for the partitions considered, before any parsing
+pub fn lsblk_json() -> Result<String, ChrootError> {
+    let result = Exec::cmd("lsblk")
+        .args(&[
+            "-f",
+            "-o",
+            "NAME,FSTYPE,UUID,PARTUUID,LABEL,PARTLABEL,PARTTYPE,SIZE",
+            "-b",
+            "-p",
+            "-a",
+            "-J",
+            "-Q",
+            "type=='part' || type=='crypt' && fstype!='swap' && fstype",
+        ])
+        .capture();
+    match result {
+        Ok(capture) if capture.success() => Ok(capture.stdout_str()),
+        _ => Err(ChrootError::Lsblk { reason: "failed to run lsblk".to_owned() }),
+    }
+}
+
+pub fn enumerate_devices(
+    ignored_devices: Option<Vec<BlockDevice>>,
+) -> Result<Vec<BlockDevice>, ChrootError> {
+    let disks_raw = lsblk_json()?;
+
+    let disks: block_device::BlockDevices = serde_json::from_str(&disks_raw)
+        .map_err(|err| ChrootError::Lsblk { reason: format!("unexpected output: {}", err) })?;
+
+    let ignored_devices = ignored_devices.unwrap_or_default();
+    let (mut block_devices, incomplete): (Vec<_>, Vec<_>) = disks
+        .into_flat()
+        .into_iter()
+        .partition(|device| !device.uuid.is_empty() || device.partuuid.is_some());
+    // Partitions without a detected filesystem are kept, they can still be mounted with
+    // --root-fstype or after confirming, but without any ID they can't be told apart
+    if !incomplete.is_empty() {
+        log::warn!("Dropped {} block devices without a UUID or PARTUUID", incomplete.len());
+    }
+    block_devices.sort_by(|a, b| block_device::compare_device_names(&a.name, &b.name));
+
+    if ignored_devices.is_empty() {
+        return Ok(block_devices);
+    }
+
+    Ok(block_devices.into_iter().filter(|d| !ignored_devices.contains(d)).collect())
+}
+
+/// Counts the devices per filesystem type, e.g. `2 btrfs, 1 crypto_LUKS, 1 vfat`, so users can
+/// check at a glance that everything they expect was found
+pub fn summarize_fs_types(block_devices: &[BlockDevice]) -> String {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for device in block_devices {
+        let fs_type = if device.has_known_fs_type() { device.fs_type.as_str() } else { "unknown" };
+        *counts.entry(fs_type).or_default() += 1;
+    }
+    counts
+        .iter()
+        .map(|(fs_type, count)| format!("{} {}", count, fs_type))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Makes the kernel re-read the partition tables, so partitions created since boot show up in
+/// lsblk. Uses partprobe and falls back to `blockdev --rereadpt` on every disk without it.
+pub fn rescan_partitions() {
+    log::info!("Rescanning partition tables...");
+    if which::which("partprobe").is_ok() {
+        if !matches!(Exec::cmd("partprobe").join(), Ok(status) if status.success()) {
+            log::warn!("partprobe failed, some partitions may be missing");
+        }
+    } else {
+        log::warn!("Command partprobe not found (install parted), falling back to blockdev");
+        let result = Exec::cmd("lsblk").args(&["-d", "-n", "-p", "-o", "NAME,TYPE"]).capture();
+        let disks = result.map(|capture| capture.stdout_str()).unwrap_or_default();
+        for line in disks.lines() {
+            let parts = line.split_whitespace().collect::<Vec<_>>();
+            if parts.len() != 2 || parts[1] != "disk" {
+                continue;
+            }
+            let result = Exec::cmd("blockdev").args(&["--rereadpt", parts[0]]).join();
+            if !matches!(result, Ok(status) if status.success()) {
+                log::warn!("Failed to re-read the partition table of {}", parts[0]);
+            }
+        }
+    }
+    // Wait for udev to create the device nodes of new partitions before listing them
+    let _ = Exec::cmd("udevadm").arg("settle").join();
+}
+
+/// Opens a LUKS device unless it was already unlocked during this run, the mapping is named
+/// `mapper_name` if given and `luks-<uuid>` otherwise. Returns the name of the mapping in use.
+pub fn unlock_luks_device(
+    device: &BlockDevice,
+    mapper_name: Option<&str>,
+    cache: &mut DiscoveryCache,
+    registry: &mut MountRegistry,
+) -> Result<String, ChrootError> {
+    if cache.unlocked_luks_devices.contains(&device.uuid) {
+        log::info!("LUKS encrypted partition {} is already open", device.name);
+        let opened_as = registry
+            .opened_luks_devices
+            .iter()
+            .find(|(opened, _)| opened.uuid == device.uuid)
+            .map(|(_, mapper_name)| mapper_name.clone());
+        return Ok(opened_as.unwrap_or_else(|| luks::default_mapper_name(device)));
+    }
+    let mapper_name =
+        mapper_name.map(str::to_owned).unwrap_or_else(|| luks::default_mapper_name(device));
+    progress::emit(ProgressEvent::UnlockingLuks { device: device.name.clone() });
+    luks::open_device(device, &mapper_name, registry.luks_key_files.for_device(device))?;
+    cache.unlocked_luks_devices.insert(device.uuid.clone());
+    registry.opened_luks_devices.push((device.clone(), mapper_name.clone()));
+    Ok(mapper_name)
+}
+
+/// Opens the LUKS device behind a `/dev/mapper/<name>` fstab entry under the name the target's
+/// crypttab expects, returns the path of the mapping or `None` if the entry isn't one of those
+fn open_crypttab_device(
+    fs_spec: &str,
+    block_devices: &[BlockDevice],
+    crypttab_entries: &HashMap<String, String>,
+    cache: &mut DiscoveryCache,
+    registry: &mut MountRegistry,
+) -> Result<Option<String>, ChrootError> {
+    let Some(mapper_name) = fs_spec.strip_prefix("/dev/mapper/") else {
+        return Ok(None);
+    };
+    let Some(source) = crypttab_entries.get(mapper_name) else {
+        return Ok(None);
+    };
+    let Some(device) = block_devices
+        .iter()
+        .find(|d| d.fs_type == "crypto_LUKS" && (d.uuid == *source || d.name == *source))
+    else {
+        return Ok(None);
+    };
+    let mapper_name = unlock_luks_device(device, Some(mapper_name), cache, registry)?;
+    Ok(Some(luks::mapped_device_path(&mapper_name)))
+}
+
+/// Mounts the root at a new temporary directory, `fs_type` forces the filesystem driver
+pub fn mount_root(
+    device: &BlockDevice,
+    fs_type: Option<&str>,
+    options: Vec<String>,
+    registry: &mut MountRegistry,
+) -> Result<String, ChrootError> {
+    progress::emit(ProgressEvent::MountingRoot { device: device.name.clone() });
+    let root_mount_point =
+        mount::create_temp_mount_point(&format!("cachyos-chroot-root-mount-{}-", &device.uuid))?;
+
+    let options = registry.device_options.merge(device, Some(options));
+    mount::mount_block_device_as(device, fs_type, &root_mount_point, options)?;
+    registry.root_mount_point = Some(root_mount_point.clone());
+
+    Ok(root_mount_point)
+}
+
+/// The root has to be mounted before its /etc/fstab can be read, so the options the installed
+/// system expects for `/` (e.g. `compress=zstd:1`) are applied afterwards by remounting it. The
+/// subvolume options are left out as the subvolume is already chosen by the initial mount. The
+/// options the user gave for the root (e.g. `ro` or `rescue=all`) are applied on top, so the
+/// remount does not undo them.
+pub fn remount_root_from_fstab(
+    root_mount_point: &str,
+    fstab_path: &Path,
+    user_options: &[String],
+) -> Result<(), ChrootError> {
+    let fstab = FsTab::new(fstab_path);
+    let entries = fstab.get_entries().unwrap_or_default();
+    let Some(root_entry) = entries.iter().find(|entry| entry.mountpoint == Path::new("/")) else {
+        log::warn!("No entry for / found in /etc/fstab, keeping current root mount options");
+        return Ok(());
+    };
+    let options = root_entry.mount_options.iter().chain(user_options).cloned().collect::<Vec<_>>();
+    let options = mount::normalize_mount_options(&options)
+        .into_iter()
+        .filter(|opt| !opt.starts_with("subvol=") && !opt.starts_with("subvolid="))
+        .collect::<Vec<_>>();
+    if options.is_empty() {
+        return Ok(());
+    }
+    remount_block_device(root_mount_point, &options)
+}
+
+/// Leaves out swap and orders the entries so parents are mounted before their children, e.g. a
+/// `subvol=@` `/` before a `subvol=@/home` `/home`, no matter how they are ordered in the file
+fn fstab_mount_order(entries: Vec<FsEntry>) -> Vec<FsEntry> {
+    let mut entries = entries
+        .into_iter()
+        .filter(|entry| entry.vfs_type != "swap")
+        .filter(|entry| {
+            // Virtual filesystems like tmpfs or proc have a placeholder instead of a device, the
+            // chroot sets up what it needs of them itself
+            let has_device = entry.fs_spec.starts_with("/dev") || entry.fs_spec.contains('=');
+            if !has_device {
+                log::debug!(
+                    "Skipping {} at {}, it is not on a block device",
+                    entry.fs_spec,
+                    entry.mountpoint.display()
+                );
+            }
+            has_device
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.mountpoint.components().count());
+    entries
+}
+
+/// Decodes the octal escapes fstab uses for spaces and other special characters, e.g. `\040`
+fn unescape_fstab(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i..i + 4)
+            .filter(|chunk| chunk[0] == b'\\')
+            .and_then(|chunk| std::str::from_utf8(&chunk[1..]).ok())
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 4;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Finds the device for the value of a `UUID=`/`PARTUUID=`/`LABEL=`/`PARTLABEL=` fstab spec
+fn find_fstab_device<'a>(value: &str, block_devices: &'a [BlockDevice]) -> Option<&'a BlockDevice> {
+    let value = unescape_fstab(value);
+    block_devices.iter().find(|d| {
+        d.uuid == value
+            || d.partuuid.as_ref() == Some(&value)
+            || d.label.as_ref() == Some(&value)
+            || d.partlabel.as_ref() == Some(&value)
+    })
+}
+
+/// Whether the strings are equal except for one inserted, removed or changed character
+fn differs_by_one_char(a: &str, b: &str) -> bool {
+    let (a, b) = (a.chars().collect::<Vec<_>>(), b.chars().collect::<Vec<_>>());
+    let (shorter, longer) = if a.len() <= b.len() { (&a, &b) } else { (&b, &a) };
+    if longer.len() - shorter.len() > 1 {
+        return false;
+    }
+    let prefix = shorter.iter().zip(longer.iter()).take_while(|(a, b)| a == b).count();
+    if prefix == longer.len() {
+        return false;
+    }
+    // Skip the differing character in the longer one, and in the shorter one too if it was changed
+    let skip_shorter = if shorter.len() == longer.len() { 1 } else { 0 };
+    shorter[(prefix + skip_shorter).min(shorter.len())..] == longer[prefix + 1..]
+}
+
+/// Devices an fstab entry that matched nothing may have meant: ones whose UUID, label or path is
+/// off by a single character (a typo) first, then ones with the same filesystem type, e.g. after
+/// the partition was reformatted and got a new UUID
+fn suggest_devices<'a>(entry: &FsEntry, block_devices: &'a [BlockDevice]) -> Vec<&'a BlockDevice> {
+    let spec = entry.fs_spec.split_once('=').map_or(entry.fs_spec.as_str(), |(_, value)| value);
+    let is_near_match = |device: &BlockDevice| {
+        [Some(&device.name), Some(&device.uuid), device.partuuid.as_ref(), device.label.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|value| differs_by_one_char(value, spec))
+    };
+    let mut suggestions =
+        block_devices.iter().filter(|device| is_near_match(device)).collect::<Vec<_>>();
+    for device in block_devices.iter().filter(|device| device.fs_type == entry.vfs_type) {
+        if !suggestions.iter().any(|suggestion| suggestion.name == device.name) {
+            suggestions.push(device);
+        }
+    }
+    suggestions
+}
+
+/// Mounts the entries of the root's /etc/fstab, `skip_mount_failure` decides whether a failed
+/// mount is skipped or aborts the whole process. `list_devices` lists the devices to look the
+/// entries up in, again after a LUKS device was unlocked, with the same filtering as every other
+/// listing.
+pub fn mount_from_fstab(
+    root_mount_point: &str,
+    crypttab_entries: &HashMap<String, String>,
+    cache: &mut DiscoveryCache,
+    show_btrfs_dot_snapshots: bool,
+    registry: &mut MountRegistry,
+    list_devices: impl Fn(&MountRegistry) -> Vec<BlockDevice>,
+    skip_mount_failure: impl Fn(&ChrootError) -> bool,
+) -> Result<(), ChrootError> {
+    progress::emit(ProgressEvent::MountingExtras);
+    log::info!("Mounting additional partitions based on /etc/fstab...");
+    let fstab = FsTab::new(&Path::new(root_mount_point).join("etc").join("fstab"));
+    let entries = fstab.get_entries().unwrap_or_default();
+    log::info!("Found {} entries in /etc/fstab", entries.len());
+    let mut block_devices = list_devices(registry);
+    for entry in &fstab_mount_order(entries) {
+        let Some(mount_point) = entry.mountpoint.to_str() else {
+            log::warn!(
+                "Mount point {} is not valid UTF-8, skipping...",
+                entry.mountpoint.display()
+            );
+            continue;
+        };
+        let device = if entry.fs_spec.starts_with("/dev") {
+            let device_path = match open_crypttab_device(
+                &entry.fs_spec,
+                &block_devices,
+                crypttab_entries,
+                cache,
+                registry,
+            ) {
+                Ok(Some(mapped_device_path)) => {
+                    block_devices = list_devices(registry);
+                    mapped_device_path
+                },
+                Ok(None) => entry.fs_spec.clone(),
+                Err(err) if skip_mount_failure(&err) => {
+                    log::warn!("{}, skipping...", err);
+                    registry.record_skipped(mount_point, "unlocking failed");
+                    continue;
+                },
+                Err(err) => return Err(err),
+            };
+            block_devices.iter().find(|d| d.name == device_path)
+        } else {
+            let fs_spec = entry.fs_spec.split('=').collect::<Vec<_>>();
+            if fs_spec.len() != 2 {
+                log::warn!("Invalid fs_spec {} in fstab, skipping...", entry.fs_spec.yellow());
+                registry.record_skipped(mount_point, "invalid fs_spec");
+                continue;
+            }
+            find_fstab_device(fs_spec[1], &block_devices)
+        };
+        if device.is_none() {
+            log::warn!("Device {} not found, skipping mounting...", entry.fs_spec.yellow());
+            let suggestions = suggest_devices(entry, &block_devices);
+            if !suggestions.is_empty() {
+                let suggestions = suggestions.iter().map(ToString::to_string).collect::<Vec<_>>();
+                log::warn!("Did you mean one of these?\n  {}", suggestions.join("\n  "));
+            }
+            registry.record_skipped(mount_point, "device not found");
+            continue;
+        }
+        let device = device.unwrap();
+        if registry.mounted_partitions.contains(&device.get_id()) {
+            log::warn!("Partition {} already mounted, skipping...", entry.fs_spec.yellow());
+            continue;
+        }
+        let existing_mounts = mount::find_existing_mounts(device, registry);
+        if !existing_mounts.is_empty() {
+            log::warn!(
+                "Partition {} is already mounted at {}",
+                device.name.yellow(),
+                existing_mounts.join(", ")
+            );
+        }
+        let actual_mount_point =
+            Path::new(root_mount_point).join(mount_point.trim_start_matches('/'));
+        let Some(actual_mount_point) = actual_mount_point.to_str() else {
+            log::warn!("Mount point {} is not valid UTF-8, skipping...", mount_point);
+            registry.record_skipped(mount_point, "invalid mount point");
+            continue;
+        };
+        if device.fs_type == "btrfs" {
+            let known_subvolumes =
+                match btrfs::get_known_subvolumes(device, cache, show_btrfs_dot_snapshots) {
+                    Ok(subvolumes) => subvolumes,
+                    Err(err) if skip_mount_failure(&err) => {
+                        log::warn!("{}, skipping...", err);
+                        registry.record_skipped(mount_point, "listing subvolumes failed");
+                        continue;
+                    },
+                    Err(err) => return Err(err),
+                };
+            let subvol_selector = btrfs::parse_btrfs_subvol_option(&entry.mount_options);
+            if subvol_selector == SubvolSelector::Unspecified {
+                log::warn!("No subvolume specified in fstab, using root subvolume");
+            }
+            let selected_subvolume = match subvol_selector.find(&known_subvolumes) {
+                Some(subvolume) => subvolume.clone(),
+                None => {
+                    log::warn!("No subvolume found for entry: {} {}", entry.fs_spec, mount_point);
+                    if !user_input::select_missing_btrfs_subvolume(mount_point) {
+                        log::warn!("Skipping mounting {}...", mount_point.yellow());
+                        registry.record_skipped(mount_point, "subvolume not found");
+                        continue;
+                    }
+                    let usage = cache.btrfs_usage.get(&device.uuid);
+                    match user_input::get_btrfs_subvolume(mount_point, &known_subvolumes, usage) {
+                        Some(subvolume) => subvolume,
+                        None => {
+                            log::warn!("Skipping mounting {}...", mount_point.yellow());
+                            registry.record_skipped(mount_point, "no subvolume selected");
+                            continue;
+                        },
+                    }
+                },
+            };
+            if registry.mounted_partitions.contains(&selected_subvolume.get_id()) {
+                log::warn!(
+                    "Partition already mounted: {} {}, skipping...",
+                    entry.fs_spec,
+                    mount_point
+                );
+                continue;
+            }
+            let options = registry.device_options.merge(
+                &selected_subvolume.device,
+                Some(vec![format!("subvolid={}", selected_subvolume.subvolume_id)]),
+            );
+            match mount_block_device(&selected_subvolume.device, actual_mount_point, options) {
+                Ok(()) => {
+                    registry.mounted_partitions.push(selected_subvolume.get_id());
+                    registry.record_mount(
+                        &selected_subvolume.device,
+                        mount_point,
+                        Some(selected_subvolume.subvolume_id),
+                    );
+                },
+                Err(err) if skip_mount_failure(&err) => {
+                    log::warn!("{}, skipping...", err);
+                    registry.record_skipped(mount_point, "mount failed");
+                },
+                Err(err) => return Err(err),
+            }
+            continue;
+        }
+        let options = if device.fs_type == "xfs" {
+            Some(xfs::external_device_options(&entry.mount_options))
+        } else {
+            None
+        };
+        let options = registry.device_options.merge(device, options);
+        match mount_block_device(device, actual_mount_point, options) {
+            Ok(()) => {
+                registry.mounted_partitions.push(device.get_id());
+                registry.record_mount(device, mount_point, None);
+            },
+            Err(err) if skip_mount_failure(&err) => {
+                log::warn!("{}, skipping...", err);
+                registry.record_skipped(mount_point, "mount failed");
+            },
+            Err(err) => return Err(err),
+        }
+    }
+    log::info!("Finished mounting additional partitions");
+    Ok(())
+}
+
+/// Collects what lsblk, blkid and for BTRFS `btrfs filesystem show` report about a device, without
+/// mounting it
+pub fn inspect_device(device: &BlockDevice) -> String {
+    let mut commands = vec![
+        Exec::cmd("lsblk").args(&[
+            "-o",
+            "NAME,FSTYPE,FSVER,LABEL,UUID,SIZE,FSUSED,MOUNTPOINTS",
+            &device.name,
+        ]),
+        Exec::cmd("blkid").arg(&device.name),
+    ];
+    if device.fs_type == "btrfs" {
+        commands.push(Exec::cmd("btrfs").args(&["filesystem", "show", &device.name]));
+    }
+    let mut info = format!("{}", device.name.yellow());
+    for command in commands {
+        let command_line = command.to_cmdline_lossy();
+        let output = match command.stderr(subprocess::Redirection::Merge).capture() {
+            Ok(capture) => capture.stdout_str(),
+            Err(err) => format!("Failed to run: {}", err),
+        };
+        info.push_str(&format!("\n$ {}\n{}", command_line, output.trim_end()));
+    }
+    info
+}
+
+/// Reads os-release of the system on the device, for --detect-os. A device that is not mounted yet
+/// is briefly mounted read-only without replaying journals, BTRFS at the top level so the `@`
+/// subvolume of the CachyOS preset can be looked into as well.
+pub fn detect_os(device: &BlockDevice) -> Result<Option<OsRelease>, ChrootError> {
+    let roots: &[&str] = if device.fs_type == "btrfs" { &["", "@"] } else { &[""] };
+    let read = |mount_point: &str| {
+        roots
+            .iter()
+            .find_map(|root| OsRelease::read(&Path::new(mount_point).join(root).to_string_lossy()))
+    };
+    if let Some(mount_point) =
+        mount::find_existing_mounts(device, &MountRegistry::default()).first()
+    {
+        return Ok(read(mount_point));
+    }
+
+    let mount_point =
+        mount::create_temp_mount_point(&format!("cachyos-chroot-os-probe-{}-", &device.uuid))?;
+    let mut options = vec!["ro".to_owned()];
+    // ro alone still replays the journal of ext3/ext4 and the log of XFS, which writes to the disk
+    match device.fs_type.as_str() {
+        "btrfs" => options.push(format!("subvolid={}", btrfs::TOP_LEVEL_SUBVOLUME_ID)),
+        "ext3" | "ext4" => options.push("noload".to_owned()),
+        "xfs" => options.push("norecovery".to_owned()),
+        _ => {},
+    }
+    mount_block_device(device, &mount_point, Some(options))?;
+    let os_release = read(&mount_point);
+    if mount::umount_block_device(&mount_point, false) {
+        let _ = fs::remove_dir(&mount_point);
+    }
+    Ok(os_release)
+}
+
+/// Builds an fstab from what was mounted during the session, referencing devices by UUID and BTRFS
+/// subvolumes by ID, so it works without genfstab. `root_mount_point` is the directory chrooted
+/// into, everything at or below `excluded_mount_points` (e.g. other OS roots mounted alongside) is
+/// left out.
+pub fn render_fstab(
+    registry: &MountRegistry,
+    root_mount_point: &str,
+    excluded_mount_points: &[&str],
+) -> String {
+    let mut entries = registry
+        .mount_table
+        .iter()
+        .filter(|entry| {
+            // The root partition is mounted above the root with --root-dir, so it has no mount
+            // point inside it
+            entry.mount_point != "/"
+                || registry.root_mount_point.as_deref().is_none_or(|root| root == root_mount_point)
+        })
+        .filter(|entry| {
+            let mount_point = Path::new(&entry.mount_point);
+            !excluded_mount_points.iter().any(|excluded| mount_point.starts_with(excluded))
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| Path::new(&entry.mount_point).components().count());
+    let mut fstab = String::from("# Generated by cachy-chroot from the mounted partitions\n");
+    for entry in entries {
+        let mut options = vec!["defaults".to_owned()];
+        if let Some(subvolume_id) = entry.subvolume_id {
+            options.push(format!("subvolid={}", subvolume_id));
+        }
+        // fsck can't check BTRFS, the root is checked first and everything else afterwards
+        let pass = match (entry.device.fs_type.as_str(), entry.mount_point.as_str()) {
+            ("btrfs", _) => 0,
+            (_, "/") => 1,
+            _ => 2,
+        };
+        let spec = match &entry.device.partuuid {
+            Some(partuuid) if entry.device.uuid.is_empty() => format!("PARTUUID={}", partuuid),
+            _ => format!("UUID={}", entry.device.uuid),
+        };
+        let fs_type = if entry.device.has_known_fs_type() { &entry.device.fs_type } else { "auto" };
+        fstab.push_str(&format!(
+            "{}\t{}\t{}\t{}\t0 {}\n",
+            spec,
+            entry.mount_point,
+            fs_type,
+            options.join(","),
+            pass
+        ));
+    }
+    fstab
+}
+
+/// Generates an fstab for everything currently mounted below the root with genfstab
+pub fn generate_fstab(root_mount_point: &str) -> Result<String, ChrootError> {
+    let result = Exec::cmd("genfstab").args(&["-U", root_mount_point]).capture();
+    match result {
+        Ok(capture) if capture.success() => Ok(capture.stdout_str()),
+        _ => Err(ChrootError::Genfstab { mount_point: root_mount_point.to_owned() }),
+    }
+}
+
+/// Processes left running from the chroot keep the root busy, with `force` the user can choose to
+/// kill them, otherwise or if they don't the root is unmounted lazily
+fn unmount_busy_root(root_mount_point: &str, force: bool) {
+    let users = mount::find_mount_users(root_mount_point);
+    let killed = force
+        && !users.is_empty()
+        && user_input::confirm_force_umount(root_mount_point, &users)
+        && mount::kill_mount_users(root_mount_point);
+    if killed && mount::umount_tree(root_mount_point, false) {
+        return;
+    }
+    log::warn!("{} is still busy, falling back to a lazy unmount", root_mount_point);
+    if !mount::umount_tree(root_mount_point, true) {
+        log::error!("Failed to unmount {}", root_mount_point);
+    }
+}
+
+/// Unmounts the root and everything mounted below it and forgets what was skipped, opened LUKS
+/// devices are left open
+pub fn unmount_all(registry: &mut MountRegistry) {
+    if let Some(root_mount_point) = registry.root_mount_point.take() {
+        if !mount::umount_tree(&root_mount_point, false) {
+            unmount_busy_root(&root_mount_point, registry.force_umount);
+        }
+    }
+    registry.mounted_partitions.clear();
+    registry.mount_table.clear();
+    registry.skipped_mounts.clear();
+}
+
+/// Unmounts the partition mounted last below the root (the directory chrooted into) and forgets
+/// about it, so it can be mounted again. Returns its mount point, or `None` if nothing but the root
+/// is mounted or unmounting failed.
+pub fn unmount_last(registry: &mut MountRegistry, root_mount_point: &str) -> Option<String> {
+    let entry = registry.mount_table.last().filter(|entry| entry.mount_point != "/")?;
+    let actual_mount_point =
+        Path::new(root_mount_point).join(entry.mount_point.trim_start_matches('/'));
+    if !mount::umount_block_device(actual_mount_point.to_str()?, false) {
+        return None;
+    }
+    let entry = registry.mount_table.pop()?;
+    let id = match entry.subvolume_id {
+        Some(subvolume_id) => {
+            block_device::BTRFSSubVolume::new(entry.device, subvolume_id, 0, String::new()).get_id()
+        },
+        None => entry.device.get_id(),
+    };
+    registry.mounted_partitions.retain(|mounted| *mounted != id);
+    Some(entry.mount_point)
+}
+
+/// Logs what `cleanup` would have torn down along with the commands to do it by hand, in the order
+/// they have to run
+fn report_leftovers(registry: &MountRegistry) {
+    let mut commands: Vec<String> = Vec::new();
+    if let Some(root_mount_point) = &registry.root_mount_point {
+        log::info!("Left mounted: {}", root_mount_point);
+        for entry in &registry.mount_table {
+            log::info!("  {} at {}", entry.device.name, entry.mount_point);
+        }
+        commands.push(format!("umount -R {}", root_mount_point));
+    }
+    for (device, mapper_name) in registry.opened_luks_devices.iter().rev() {
+        log::info!("Left open: {} as /dev/mapper/{}", device.name, mapper_name);
+        commands.push(format!("cryptsetup close {}", mapper_name));
+    }
+    for attached_image in &registry.attached_images {
+        log::info!("Left attached: {}", attached_image.loop_device);
+        if attached_image.kpartx {
+            commands.push(format!("kpartx -d {}", attached_image.loop_device));
+        }
+        commands.push(format!("losetup -d {}", attached_image.loop_device));
+    }
+    if !commands.is_empty() {
+        log::info!("To clean up later, run: {}", commands.join(" && "));
+    }
+}
+
+/// Tears down everything in the registry and forgets about it, so running it again is a no-op
+pub(crate) fn teardown(registry: &mut MountRegistry) {
+    if registry.no_cleanup {
+        report_leftovers(registry);
+    } else {
+        unmount_all(registry);
+        // Inner layers of nested LUKS devices have to be closed before the ones they live on
+        for (device, mapper_name) in registry.opened_luks_devices.iter().rev() {
+            luks::close_device(device, mapper_name);
+        }
+        // Loop devices go last, LUKS devices inside an image keep them busy until they are closed
+        for attached_image in &registry.attached_images {
+            image::detach_image(attached_image);
+        }
+    }
+    registry.root_mount_point = None;
+    registry.mount_table.clear();
+    registry.opened_luks_devices.clear();
+    registry.attached_images.clear();
+}
+
+/// Safe to call more than once, everything torn down is forgotten
+pub fn cleanup(registry: &mut MountRegistry) {
+    teardown(registry);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_device::BTRFSSubVolume;
+
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    const MULTI_SUBVOLUME_FSTAB: &str = "\
+UUID=abcd /home/user/.cache btrfs subvol=/@cache,noatime 0 0
+UUID=abcd /home btrfs subvol=/@home,noatime 0 0
+UUID=swap none swap defaults 0 0
+UUID=abcd / btrfs subvol=/@,noatime 0 0
+UUID=abcd /.snapshots btrfs subvolid=259 0 0
+tmpfs /tmp tmpfs defaults,noatime,mode=1777 0 0
+";
+
+    fn read_fstab(contents: &str) -> Vec<FsEntry> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("fstab");
+        fs::write(&path, contents).unwrap();
+        FsTab::new(&path).get_entries().unwrap()
+    }
+
+    fn subvolumes() -> Vec<BTRFSSubVolume> {
+        let device = BlockDevice {
+            name: "/dev/sda2".to_owned(),
+            fs_type: "btrfs".to_owned(),
+            uuid: "abcd".to_owned(),
+            partuuid: None,
+            label: None,
+            partlabel: None,
+            parttype: None,
+            size: None,
+        };
+        [(5, 0, "/"), (256, 5, "@"), (257, 5, "@home"), (258, 5, "@cache"), (259, 5, "@snapshots")]
+            .into_iter()
+            .map(|(id, parent_id, name)| {
+                BTRFSSubVolume::new(device.clone(), id, parent_id, name.to_owned())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fstab_entries_are_mounted_parents_first() {
+        let entries = fstab_mount_order(read_fstab(MULTI_SUBVOLUME_FSTAB));
+        let mount_points =
+            entries.iter().map(|entry| entry.mountpoint.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(mount_points, vec!["/", "/home", "/.snapshots", "/home/user/.cache"]);
+    }
+
+    #[test]
+    fn fstab_subvolumes_resolve_to_distinct_ids() {
+        let subvolumes = subvolumes();
+        let ids = fstab_mount_order(read_fstab(MULTI_SUBVOLUME_FSTAB))
+            .iter()
+            .map(|entry| {
+                let selector = btrfs::parse_btrfs_subvol_option(&entry.mount_options);
+                selector.find(&subvolumes).unwrap().get_id()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["abcd-256", "abcd-257", "abcd-259", "abcd-258"]);
+    }
+
+    #[test]
+    fn fstab_subvolumes_resolve_with_tabs_and_spaces() {
+        let subvolumes = subvolumes();
+        let fstab = "\tUUID=abcd\t/home\tbtrfs\tnoatime,subvolid=257\t0\t0\n  UUID=abcd   /   \
+                     btrfs   noatime,,subvolid=256,   0   0\n";
+        let ids = fstab_mount_order(read_fstab(fstab))
+            .iter()
+            .map(|entry| {
+                let selector = btrfs::parse_btrfs_subvol_option(&entry.mount_options);
+                selector.find(&subvolumes).unwrap().get_id()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["abcd-256", "abcd-257"]);
+    }
+
+    #[test]
+    fn fstab_octal_escapes_are_decoded() {
+        assert_eq!(unescape_fstab("My\\040Disk"), "My Disk");
+        assert_eq!(unescape_fstab("tab\\011and\\134backslash"), "tab\tand\\backslash");
+        assert_eq!(unescape_fstab("no\\escape\\09"), "no\\escape\\09");
+    }
+
+    #[test]
+    fn fstab_labels_with_spaces_resolve() {
+        let mut device = subvolumes()[0].device.clone();
+        device.label = Some("My Disk".to_owned());
+        let block_devices = vec![device];
+        let entries = read_fstab("LABEL=My\\040Disk /data btrfs defaults 0 0\n");
+        let fs_spec = entries[0].fs_spec.split_once('=').unwrap().1;
+        assert_eq!(fs_spec, "My\\040Disk");
+        let found = find_fstab_device(fs_spec, &block_devices).unwrap();
+        assert_eq!(found.name, "/dev/sda2");
+    }
+
+    #[test]
+    fn same_subvolume_by_name_and_id_is_deduplicated() {
+        let subvolumes = subvolumes();
+        let by_name = SubvolSelector::Name("@".to_owned()).find(&subvolumes).unwrap();
+        let by_id = SubvolSelector::Id(256).find(&subvolumes).unwrap();
+        assert_eq!(by_name.get_id(), by_id.get_id());
+        assert_ne!(by_name.get_id(), subvolumes[0].device.get_id());
+    }
+
+    #[test]
+    fn rendered_fstab_leaves_out_other_roots_and_the_partition_above_the_root() {
+        let subvolumes = subvolumes();
+        let device = &subvolumes[0].device;
+        let mut registry = MountRegistry::default();
+        registry.root_mount_point = Some("/tmp/root".to_owned());
+        registry.no_cleanup = true;
+        registry.record_mount(device, "/", Some(256));
+        registry.record_mount(device, "/home", Some(257));
+        registry.record_mount(device, "/mnt/other", Some(258));
+
+        let fstab = render_fstab(&registry, "/tmp/root", &["/mnt/other"]);
+        let mount_points =
+            fstab.lines().skip(1).filter_map(|line| line.split('\t').nth(1)).collect::<Vec<_>>();
+        assert_eq!(mount_points, ["/", "/home"]);
+
+        let fstab = render_fstab(&registry, "/tmp/root/rootfs", &[]);
+        let mount_points =
+            fstab.lines().skip(1).filter_map(|line| line.split('\t').nth(1)).collect::<Vec<_>>();
+        assert_eq!(mount_points, ["/home", "/mnt/other"]);
+    }
+}
@@ -21,13 +21,18 @@ impl log::Log for SimpleLogger {
                 Level::Debug => "Debug:".white(),
                 Level::Trace => "Trace:".black(),
             };
-            println!("{} {}", level_str, record.args());
+            if record.level() == Level::Error {
+                eprintln!("{} {}", level_str, record.args());
+            } else {
+                println!("{} {}", level_str, record.args());
+            }
         }
     }
 
     fn flush(&self) {
         use std::io::Write;
         io::stdout().flush().unwrap();
+        io::stderr().flush().unwrap();
     }
 }
 
@@ -1,29 +1,115 @@
-use crate::{block_device, utils};
+use crate::error::ChrootError;
+use crate::{block_device, user_input};
 
 use std::collections::HashMap;
-use std::fs;
 use std::path::PathBuf;
+use std::{env, fs};
 
-use subprocess::Exec;
+use subprocess::{Exec, ExitStatus};
 
-pub fn open_device(device: &block_device::BlockDevice) -> bool {
-    log::info!("Opening LUKS encrypted partition {}", device.name);
-    let result = Exec::cmd("cryptsetup")
-        .args(&["luksOpen", &device.name, &format!("luks-{}", &device.uuid)])
-        .join();
-    if result.is_err() || !result.unwrap().success() {
-        utils::print_error_and_exit(&format!(
-            "Failed to open LUKS encrypted partition {}",
-            device.name
-        ));
+pub fn default_mapper_name(device: &block_device::BlockDevice) -> String {
+    format!("luks-{}", &device.uuid)
+}
+
+pub fn mapped_device_path(mapper_name: &str) -> String {
+    format!("/dev/mapper/{}", mapper_name)
+}
+
+/// Looks up the name the target's /etc/crypttab gives the mapping of a LUKS device, so tooling
+/// run inside the chroot (e.g. mkinitcpio) sees the same names as on a regular boot
+pub fn crypttab_mapper_name<'a>(
+    device: &block_device::BlockDevice,
+    crypttab_entries: &'a HashMap<String, String>,
+) -> Option<&'a str> {
+    crypttab_entries
+        .iter()
+        .find(|(_, source)| **source == device.uuid || **source == device.name)
+        .map(|(mapper_name, _)| mapper_name.as_str())
+}
+
+/// Key files passed with `--luks-key-file DEVICE:PATH` and `--luks-key-file-all PATH`, so devices
+/// can be opened without the passphrase prompt
+#[derive(Default)]
+pub struct KeyFiles {
+    per_device: Vec<(String, String)>,
+    fallback: Option<String>,
+}
+
+impl KeyFiles {
+    pub fn parse(specs: &[String], fallback: Option<String>) -> Result<Self, String> {
+        let per_device = specs
+            .iter()
+            .map(|spec| match spec.split_once(':') {
+                Some((device, path)) if !device.is_empty() && !path.is_empty() => {
+                    Ok((device.to_owned(), path.to_owned()))
+                },
+                _ => Err(format!("Invalid LUKS key file {}, expected DEVICE:PATH", spec)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(KeyFiles { per_device, fallback })
+    }
+
+    /// A key file given for the device itself wins over the one for all devices
+    pub fn for_device(&self, device: &block_device::BlockDevice) -> Option<&str> {
+        self.per_device
+            .iter()
+            .find(|(spec, _)| device.matches_spec(spec))
+            .map(|(_, path)| path.as_str())
+            .or(self.fallback.as_deref())
+    }
+}
+
+/// Passphrase used for LUKS devices without a key file instead of the prompt, for unattended runs.
+/// Any process of the same user (and root) can read the environment, so a key file with tight
+/// permissions is the safer choice where one can be used. The value is never logged.
+pub const PASSPHRASE_ENV: &str = "CACHY_LUKS_PASSPHRASE";
+
+/// How often the user is offered to enter the passphrase again after cryptsetup gave up
+const MAX_PASSPHRASE_RETRIES: usize = 3;
+
+/// cryptsetup exits with this when no key slot could be opened with the passphrase or key file
+const CRYPTSETUP_NO_KEY_EXIT_CODE: u32 = 2;
+
+/// Opens the device with the key file if given, then with the passphrase from [`PASSPHRASE_ENV`],
+/// otherwise cryptsetup asks for the passphrase. When the passphrase or key file is wrong the user
+/// can retry with a passphrase a few times.
+pub fn open_device(
+    device: &block_device::BlockDevice,
+    mapper_name: &str,
+    key_file: Option<&str>,
+) -> Result<(), ChrootError> {
+    log::info!("Opening LUKS encrypted partition {} as {}", device.name, mapper_name);
+    let mut key_file = key_file;
+    let mut passphrase = env::var(PASSPHRASE_ENV).ok().filter(|passphrase| !passphrase.is_empty());
+    let mut retries = 0;
+    loop {
+        let mut args = vec!["luksOpen", &device.name, mapper_name];
+        let mut exec = Exec::cmd("cryptsetup");
+        if let Some(key_file) = key_file {
+            args.extend(["--key-file", key_file]);
+        } else if let Some(passphrase) = &passphrase {
+            log::info!("Using the passphrase from {}", PASSPHRASE_ENV);
+            // cryptsetup reads the passphrase up to the first newline when stdin is not a terminal
+            exec = exec.stdin(format!("{}\n", passphrase).as_str());
+        }
+        match exec.args(&args).join() {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(ExitStatus::Exited(CRYPTSETUP_NO_KEY_EXIT_CODE))
+                if retries < MAX_PASSPHRASE_RETRIES
+                    && user_input::retry_passphrase("LUKS encrypted partition", &device.name) =>
+            {
+                retries += 1;
+                key_file = None;
+                passphrase = None;
+            },
+            _ => return Err(ChrootError::LuksOpen { device: device.name.clone() }),
+        }
     }
-    true
 }
 
-pub fn close_device(device: &block_device::BlockDevice) -> bool {
+pub fn close_device(device: &block_device::BlockDevice, mapper_name: &str) -> bool {
     log::info!("Closing LUKS encrypted partition {}", device.name);
-    let result =
-        Exec::cmd("cryptsetup").args(&["luksClose", &format!("luks-{}", &device.uuid)]).join();
+    let result = Exec::cmd("cryptsetup").args(&["luksClose", mapper_name]).join();
     if result.is_err() || !result.unwrap().success() {
         log::warn!("Failed to close LUKS encrypted partition {}", device.name);
     }
@@ -1,160 +1,598 @@
-pub mod args;
-pub mod block_device;
-pub mod logger;
-pub mod luks;
-pub mod user_input;
-pub mod utils;
+mod args;
 
-use block_device::{BTRFSSubVolume, BlockDevice, BlockOrSubvolumeID};
+use cachy_chroot::block_device::{BTRFSSubVolume, BlockDevice, BlockOrSubvolumeID, DiscoveryCache};
+use cachy_chroot::depends::Features;
+use cachy_chroot::error::ChrootError;
+use cachy_chroot::mount::{self, mount_block_device, MountRegistry};
+use cachy_chroot::os_release::{self, OsRelease};
+use cachy_chroot::progress::ProgressEvent;
+use cachy_chroot::user_input::{AdditionalMountAction, ReadOnlySnapshotAction};
+use cachy_chroot::{
+    btrfs, depends, enumerate, image, logger, luks, progress, repair, user_input, utils, xfs,
+};
 
-use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Component, Path};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
 
 use clap::Parser;
-use colored::Colorize;
-use fstab::FsTab;
 use nix::unistd::Uid;
-use subprocess::Exec;
-use tempfile::TempDir;
+use subprocess::{Exec, ExitStatus};
 use which::which;
 
-fn mount_block_device(
+fn confirm_mount_if_in_use(device: &BlockDevice, registry: &MountRegistry) -> bool {
+    let existing_mounts = mount::find_existing_mounts(device, registry);
+    if existing_mounts.is_empty() {
+        return true;
+    }
+    log::warn!("Partition {} is already mounted at {}", device.name, existing_mounts.join(", "));
+    user_input::mount_device_in_use(&device.name, &existing_mounts)
+}
+
+/// Single pipeline for listing devices, used both initially and whenever the list is refreshed
+/// (e.g. after unlocking LUKS), so every picker sees the same filtering and ordering
+fn list_block_devices(registry: &MountRegistry, features: &Features) -> Vec<BlockDevice> {
+    let block_devices =
+        match cachy_chroot::enumerate_devices(Some(registry.opened_luks_block_devices())) {
+            Ok(block_devices) => block_devices,
+            Err(err) => {
+                // Callers treat an empty list as nothing to select, which exits or skips cleanly
+                log::error!("{}", err);
+                return Vec::new();
+            },
+        };
+    let (block_devices, unsupported): (Vec<_>, Vec<_>) =
+        block_devices.into_iter().partition(|device| features.supports_fs_type(&device.fs_type));
+    for device in &unsupported {
+        log::warn!("Skipping partition {}, {} support is disabled", device.name, device.fs_type);
+    }
+    let (mut block_devices, excluded): (Vec<_>, Vec<_>) = block_devices
+        .into_iter()
+        .partition(|device| !features.is_excluded_fs_type(&device.fs_type));
+    for device in &excluded {
+        log::info!("Skipping partition {}, {} is excluded", device.name, device.fs_type);
+    }
+    // The live medium is never what should be repaired, so it goes to the end of every list
+    let live_devices = mount::find_live_system_devices();
+    block_devices.sort_by_key(|device| live_devices.contains(&device.name));
+    for device in block_devices.iter().filter(|device| live_devices.contains(&device.name)) {
+        log::info!("Listing partition {} last, it backs the running system", device.name);
+    }
+    block_devices
+}
+
+/// How often lsblk is asked again while waiting for devices with --wait-for-device
+const WAIT_FOR_DEVICE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Polls until a device matches each of the specs, exits once `timeout` passed without all of
+/// them showing up
+fn wait_for_devices(specs: &[String], timeout: Duration) {
+    let started = Instant::now();
+    let mut waiting_for: Vec<&String> = Vec::new();
+    loop {
+        let block_devices = cachy_chroot::enumerate_devices(None).unwrap_or_default();
+        let missing = specs
+            .iter()
+            .filter(|spec| !block_devices.iter().any(|device| device.matches_spec(spec)))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return;
+        }
+        if started.elapsed() >= timeout {
+            let missing = missing.iter().map(|spec| spec.as_str()).collect::<Vec<_>>();
+            utils::print_error_and_exit(&format!("Timed out waiting for {}", missing.join(", ")));
+        }
+        if missing != waiting_for {
+            let names = missing.iter().map(|spec| spec.as_str()).collect::<Vec<_>>();
+            log::info!("Waiting for {} to appear...", names.join(", "));
+            waiting_for = missing;
+        }
+        thread::sleep(WAIT_FOR_DEVICE_INTERVAL);
+    }
+}
+
+/// Filesystems considered by --select-by-size, LUKS is included so an encrypted root can still be
+/// picked and unlocked
+const LINUX_FS_TYPES: [&str; 8] =
+    ["ext4", "ext3", "ext2", "btrfs", "xfs", "f2fs", "bcachefs", "crypto_LUKS"];
+
+fn largest_linux_partition(block_devices: &[BlockDevice]) -> Option<&BlockDevice> {
+    let live_devices = mount::find_live_system_devices();
+    block_devices
+        .iter()
+        .filter(|device| LINUX_FS_TYPES.contains(&device.fs_type.as_str()))
+        .filter(|device| !live_devices.contains(&device.name))
+        // The partition type of e.g. /home or /var rules it out as root, however large it is
+        .filter(|device| device.suggested_mount_point().is_none())
+        .max_by_key(|device| (device.is_discoverable_root(), device.size.unwrap_or_default()))
+}
+
+fn select_root_device<'a>(
+    block_devices: &'a [BlockDevice],
+    root_spec: Option<&str>,
+    auto_root: bool,
+    select_by_size: bool,
+    registry: &mut MountRegistry,
+) -> &'a BlockDevice {
+    if let Some(root_spec) = root_spec {
+        let Some(device) = block_devices.iter().find(|device| device.matches_spec(root_spec))
+        else {
+            exit_with_cleanup(registry, &format!("No block device matches --root {}", root_spec));
+        };
+        log::info!("Selected {} as root", device.name);
+        return device;
+    }
+    if auto_root {
+        let mut discoverable_roots =
+            block_devices.iter().filter(|device| device.is_discoverable_root());
+        match (discoverable_roots.next(), discoverable_roots.next()) {
+            (Some(device), None) => {
+                log::info!("Selected {} as root, its partition type marks it as root", device.name);
+                return device;
+            },
+            (Some(_), Some(_)) => log::warn!("More than one partition is marked as root"),
+            (None, _) => log::warn!("No partition is marked as root by its partition type"),
+        }
+    }
+    if select_by_size {
+        if let Some(device) = largest_linux_partition(block_devices) {
+            log::info!("Selected {} as root, it is the largest Linux partition", device.name);
+            return device;
+        }
+        log::warn!("No Linux partition found to select as root by size");
+    }
+    user_input::get_block_device("root", block_devices, false).unwrap_or_else(|| {
+        exit_with_cleanup(
+            registry,
+            "No root partition was selected, select one or specify it with --root",
+        )
+    })
+}
+
+/// Tears down what was set up so far before exiting on an error, `process::exit` skips the Drop of
+/// the registry
+fn exit_with_cleanup(registry: &mut MountRegistry, msg: &str) -> ! {
+    cachy_chroot::cleanup(registry);
+    utils::print_error_and_exit(msg)
+}
+
+/// Snapper snapshots are usually read-only, which makes arch-chroot and writes inside the chroot
+/// fail, so the user is told about it and asked how to mount it
+fn read_only_snapshot_action(subvolume: &BTRFSSubVolume) -> ReadOnlySnapshotAction {
+    if !btrfs::is_snapper_snapshot(subvolume) {
+        return ReadOnlySnapshotAction::MountAsIs;
+    }
+    match btrfs::is_read_only_subvolume(subvolume) {
+        Ok(true) => {
+            log::warn!(
+                "Selected a read-only snapshot, arch-chroot and writes inside the chroot will \
+                 fail unless a writable copy is used"
+            );
+            user_input::select_read_only_snapshot_action(&subvolume.subvolume_name)
+        },
+        Ok(false) => ReadOnlySnapshotAction::MountAsIs,
+        Err(err) => {
+            log::warn!("{}", err);
+            ReadOnlySnapshotAction::MountAsIs
+        },
+    }
+}
+
+/// Devices can disappear between enumeration and mounting, e.g. an unplugged USB drive
+fn device_present(device: &BlockDevice) -> bool {
+    if Path::new(&device.name).exists() {
+        return true;
+    }
+    log::warn!("Device {} no longer present, re-enumerating", device.name);
+    false
+}
+
+fn confirm_unknown_filesystem(device: &BlockDevice) -> bool {
+    if device.has_known_fs_type() {
+        return true;
+    }
+    log::warn!(
+        "Partition {} has no recognized filesystem, mounting it will likely fail",
+        device.name
+    );
+    user_input::mount_unknown_filesystem(&device.name)
+}
+
+fn mount_or_skip(
     device: &BlockDevice,
     mount_point: &str,
-    gracefully_fail: bool,
+    actual_mount_point: &str,
     options: Option<Vec<String>>,
+    registry: &mut MountRegistry,
 ) -> bool {
-    let options = options.unwrap_or_default();
-    log::info!("Mounting partition {} at {} with options: {:?}", device.name, mount_point, options);
-    let result = Exec::cmd("mount").arg(&device.name).arg(mount_point).args(&options).join();
-    if result.is_err() || !result.unwrap().success() {
-        if gracefully_fail && user_input::continue_on_mount_failure() {
-            log::warn!("Failed to mount partition {} at {}, skipping...", device.name, mount_point);
-            return false;
-        } else {
-            utils::print_error_and_exit(&format!(
-                "Failed to mount partition {} at {}",
-                device.name, mount_point
-            ));
+    let options = registry.device_options.merge(device, options);
+    match mount_block_device(device, actual_mount_point, options) {
+        Ok(()) => true,
+        Err(err) if user_input::continue_on_mount_failure() => {
+            log::warn!("{}, skipping...", err);
+            registry.record_skipped(mount_point, "mount failed");
+            false
+        },
+        Err(err) => exit_with_cleanup(registry, &err.to_string()),
+    }
+}
+
+fn mount_additional_partition(
+    device: &BlockDevice,
+    mount_point: &str,
+    root_mount_point: &str,
+    cache: &mut DiscoveryCache,
+    show_btrfs_dot_snapshots: bool,
+    registry: &mut MountRegistry,
+) {
+    if registry.mounted_partitions.contains(&device.get_id()) {
+        log::warn!("Partition already mounted, skipping...");
+        return;
+    }
+    if !confirm_unknown_filesystem(device) {
+        log::warn!("Skipping mounting partition {}...", device.name);
+        registry.record_skipped(mount_point, "unknown filesystem");
+        return;
+    }
+    // Checked before the BTRFS subvolume probe, which mounts the device too
+    if !confirm_mount_if_in_use(device, registry) {
+        log::warn!("Skipping mounting partition {}...", device.name);
+        registry.record_skipped(mount_point, "already in use");
+        return;
+    }
+    let actual_mount_point = Path::new(root_mount_point).join(mount_point.trim_start_matches('/'));
+    let Some(actual_mount_point) = actual_mount_point.to_str() else {
+        log::warn!("Mount point {} is not valid UTF-8, skipping...", mount_point);
+        registry.record_skipped(mount_point, "invalid mount point");
+        return;
+    };
+    if device.fs_type == "btrfs" {
+        let selected_subvolume = match btrfs::get_btrfs_subvolume(
+            device,
+            cache,
+            show_btrfs_dot_snapshots,
+            mount_point,
+        ) {
+            Ok(selected_subvolume) => selected_subvolume,
+            Err(err @ ChrootError::SubvolumeList { requires_root: true, .. }) => {
+                log::error!("{}, mounting the default subvolume instead", err);
+                if mount_or_skip(device, mount_point, actual_mount_point, None, registry) {
+                    registry.mounted_partitions.push(device.get_id());
+                    registry.record_mount(device, mount_point, None);
+                }
+                return;
+            },
+            Err(err @ ChrootError::SubvolumeNotSelected { .. }) => {
+                log::warn!("{}, skipping...", err);
+                registry.record_skipped(mount_point, "no subvolume selected");
+                return;
+            },
+            Err(err) => exit_with_cleanup(registry, &err.to_string()),
+        };
+        if registry.mounted_partitions.contains(&selected_subvolume.get_id()) {
+            log::warn!("Partition already mounted, skipping...");
+            return;
+        }
+        if mount_or_skip(
+            &selected_subvolume.device,
+            mount_point,
+            actual_mount_point,
+            Some(vec![format!("subvolid={}", selected_subvolume.subvolume_id)]),
+            registry,
+        ) {
+            registry.mounted_partitions.push(selected_subvolume.get_id());
+            registry.record_mount(
+                &selected_subvolume.device,
+                mount_point,
+                Some(selected_subvolume.subvolume_id),
+            );
+        }
+        return;
+    }
+    let options = xfs::ask_external_device_options(device);
+    if mount_or_skip(device, mount_point, actual_mount_point, Some(options), registry) {
+        registry.mounted_partitions.push(device.get_id());
+        registry.record_mount(device, mount_point, None);
+    }
+}
+
+/// Mounts another OS root given as `SPEC:PATH` with --also-mount-root at PATH below the root, with
+/// the same LUKS and BTRFS subvolume handling as the root but without chrooting into it
+fn mount_also_root(
+    spec_path: &str,
+    block_devices: &mut Vec<BlockDevice>,
+    root_mount_point: &str,
+    cache: &mut DiscoveryCache,
+    show_btrfs_dot_snapshots: bool,
+    features: &Features,
+    registry: &mut MountRegistry,
+) {
+    let Some((spec, mount_point)) =
+        spec_path.split_once(':').filter(|(spec, path)| !spec.is_empty() && path.starts_with('/'))
+    else {
+        log::error!("Invalid --also-mount-root {}, expected DEVICE:/PATH", spec_path);
+        return;
+    };
+    let Some(mut device) = block_devices.iter().find(|device| device.matches_spec(spec)).cloned()
+    else {
+        log::error!("No block device matches --also-mount-root {}, skipping...", spec);
+        registry.record_skipped(mount_point, "device not found");
+        return;
+    };
+    while device.fs_type == "crypto_LUKS" {
+        let mapper_name = match cachy_chroot::unlock_luks_device(&device, None, cache, registry) {
+            Ok(mapper_name) => mapper_name,
+            Err(err) => {
+                log::error!("{}, skipping...", err);
+                registry.record_skipped(mount_point, "unlocking failed");
+                return;
+            },
+        };
+        *block_devices = list_block_devices(registry, features);
+        let mapped_device_path = luks::mapped_device_path(&mapper_name);
+        match block_devices.iter().find(|d| d.name == mapped_device_path) {
+            Some(mapped_device) => device = mapped_device.clone(),
+            None => {
+                log::warn!("Unable to find {}, skipping...", mapped_device_path);
+                registry.record_skipped(mount_point, "device not found");
+                return;
+            },
         }
     }
-    true
+    let target = Path::new(root_mount_point).join(mount_point.trim_start_matches('/'));
+    if let Err(err) = fs::create_dir_all(&target) {
+        log::error!("Failed to create {}: {}, skipping...", target.display(), err);
+        registry.record_skipped(mount_point, "creating the mount point failed");
+        return;
+    }
+    mount_additional_partition(
+        &device,
+        mount_point,
+        root_mount_point,
+        cache,
+        show_btrfs_dot_snapshots,
+        registry,
+    );
 }
 
-fn umount_block_device(mount_point: &str, recursive: bool) {
-    let args = if recursive { vec!["-R", mount_point] } else { vec![mount_point] };
-    log::info!("Unmounting partition at {}", mount_point);
-    Exec::cmd("umount").args(&args).join().expect("Failed to unmount block device");
+fn resolve_root_dir(mounted_root: &str, root_dir: &str) -> Result<String, String> {
+    let root_dir = Path::new(root_dir.trim_start_matches('/'));
+    if root_dir.components().any(|component| component == Component::ParentDir) {
+        return Err(format!("Root directory {} must not contain ..", root_dir.display()));
+    }
+    let root_path = Path::new(mounted_root).join(root_dir);
+    if !root_path.join("etc").is_dir() {
+        return Err(format!(
+            "Root directory /{} does not exist or does not contain /etc",
+            root_dir.display()
+        ));
+    }
+    root_path
+        .to_str()
+        .map(str::to_owned)
+        .ok_or_else(|| format!("Root directory /{} is not valid UTF-8", root_dir.display()))
 }
 
-fn list_subvolumes(
-    device: &BlockDevice,
-    include_dot_snapshots: bool,
-) -> Vec<BTRFSSubVolume> {
-    let tmp_dir = TempDir::with_prefix(format!("cachyos-chroot-temp-mount-{}-", &device.uuid))
-        .expect("Failed to create temporary directory");
-    let tmp_dir = tmp_dir.into_path();
-    let mount_point = tmp_dir.to_str().unwrap();
-
-    mount_block_device(device, mount_point, false, None);
-
-    let subvolumes_raw = Exec::cmd("btrfs")
-        .args(&["subvolume", "list", "-t", mount_point])
-        .capture()
-        .expect("Failed to list BTRFS subvolumes")
-        .stdout_str();
-    let subvolume_lines = subvolumes_raw.trim().split('\n').collect::<Vec<_>>();
-    let mut subvolumes = vec![BTRFSSubVolume {
-        device: device.clone(),
-        subvolume_id: 5,
-        subvolume_name: "/".to_owned(),
-    }];
-
-    for subvolume in &subvolume_lines[2..] {
-        let subvolume_parts = subvolume.split_whitespace().collect::<Vec<_>>();
-
-        if subvolume_parts.len() == 4 {
-            let subvolume_id = subvolume_parts[0];
-            let subvolume_name = subvolume_parts[3];
-            if subvolume_name.starts_with(".snapshots") && !include_dot_snapshots {
-                continue;
-            }
-            subvolumes.push(BTRFSSubVolume::new(
-                device.clone(),
-                subvolume_id.parse().unwrap(),
-                subvolume_name.to_string(),
-            ));
+fn write_generated_fstab(root_mount_point: &str) {
+    let fstab = match cachy_chroot::generate_fstab(root_mount_point) {
+        Ok(fstab) => fstab,
+        Err(err) => {
+            log::error!("{}", err);
+            return;
+        },
+    };
+    println!("{}", fstab);
+    let fstab_path = Path::new(root_mount_point).join("etc").join("fstab");
+    if !user_input::write_generated_fstab(fstab_path.exists()) {
+        return;
+    }
+    if fstab_path.exists() {
+        let backup_path = fstab_path.with_extension("bak");
+        if let Err(err) = fs::copy(&fstab_path, &backup_path) {
+            log::error!("Failed to back up /etc/fstab: {}, not overwriting it", err);
+            return;
         }
+        log::info!("Backed up the existing /etc/fstab to /etc/fstab.bak");
     }
+    match fs::write(&fstab_path, fstab) {
+        Ok(()) => log::info!("Wrote the generated fstab to /etc/fstab"),
+        Err(err) => log::error!("Failed to write /etc/fstab: {}", err),
+    }
+}
 
-    umount_block_device(mount_point, false);
+/// Files every Linux root has, with alternative locations
+const ROOT_FILES: [(&str, &[&str]); 2] =
+    [("/etc/os-release", &["etc/os-release", "usr/lib/os-release"]), ("/bin/sh", &["bin/sh"])];
 
-    subvolumes
+/// Returns the files a Linux root is expected to have that are missing, /etc/fstab only counts
+/// when it is not generated with --genfstab
+fn missing_root_files(root_mount_point: &str, require_fstab: bool) -> Vec<&'static str> {
+    let root = Path::new(root_mount_point);
+    let mut missing = ROOT_FILES
+        .iter()
+        .filter(|(_, paths)| !paths.iter().any(|path| root.join(path).exists()))
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+    if require_fstab && !root.join("etc").join("fstab").exists() {
+        missing.push("/etc/fstab");
+    }
+    missing
 }
 
-fn get_btrfs_subvolume(
-    device: &BlockDevice,
-    discovered_btrfs_subvolumes: &mut HashMap<String, Vec<BTRFSSubVolume>>,
-    show_btrfs_dot_snapshots: bool,
-    device_name: &str,
-) -> BTRFSSubVolume {
-    let known_subvolumes = if discovered_btrfs_subvolumes.contains_key(&device.uuid) {
-        discovered_btrfs_subvolumes.get(&device.uuid).unwrap().clone()
-    } else {
-        let subvolumes = list_subvolumes(device, show_btrfs_dot_snapshots);
-        discovered_btrfs_subvolumes.insert(device.uuid.clone(), subvolumes.clone());
-        subvolumes
-    };
-    let selected_subvolume = if known_subvolumes.len() == 1 {
-        log::warn!("No subvolumes found, using root subvolume");
-        known_subvolumes[0].clone()
-    } else if device_name == "root" {
-        let cachy_default_root_subvol =
-            known_subvolumes.iter().find(|subvol| subvol.subvolume_name == "@");
-        if cachy_default_root_subvol.is_some() && user_input::use_cachyos_btrfs_preset() {
-            cachy_default_root_subvol.unwrap().clone()
-        } else {
-            user_input::get_btrfs_subvolume(device_name, &known_subvolumes)
+/// Whether the root's /etc/passwd has an entry for the user
+fn user_exists(root_mount_point: &str, user: &str) -> bool {
+    let passwd = Path::new(root_mount_point).join("etc").join("passwd");
+    let passwd = fs::read_to_string(passwd).unwrap_or_default();
+    passwd.lines().any(|line| line.split(':').next() == Some(user))
+}
+
+/// Splits --arch-chroot-args on whitespace, it has to start with an option since arch-chroot
+/// expects the chroot directory after its options
+fn parse_arch_chroot_args(raw: &str) -> Result<Vec<String>, String> {
+    let extra_args = raw.split_whitespace().map(str::to_owned).collect::<Vec<_>>();
+    match extra_args.first() {
+        Some(first) if first.starts_with('-') => Ok(extra_args),
+        _ => {
+            Err(format!("Invalid arch-chroot arguments \"{}\", expected options like -u USER", raw))
+        },
+    }
+}
+
+/// Files systemd leaves when it runs inside a container, where nesting the systemd-run scope of
+/// `arch-chroot -S` usually fails
+const CONTAINER_MARKERS: [&str; 2] = ["/run/systemd/container", "/run/host/container-manager"];
+
+/// Why `arch-chroot -S` can't work for the root, if it can't
+fn systemd_chroot_unusable_reason(root_mount_point: &str) -> Option<&'static str> {
+    if !Path::new(root_mount_point).join("usr/lib/systemd/systemd").exists() {
+        return Some("the root has no systemd");
+    }
+    if CONTAINER_MARKERS.iter().any(|marker| Path::new(marker).exists()) {
+        return Some("this is running inside a container");
+    }
+    None
+}
+
+/// With `user` the shell is a login shell of that user instead of root, `extra_args` are passed
+/// to arch-chroot before the root
+fn chroot(root_mount_point: &str, user: Option<&str>, extra_args: &[String]) -> bool {
+    log::info!("Chrooting into the configured root partition...");
+    log::info!("To exit the chroot, type 'exit' or press Ctrl+D");
+
+    let mut extra_args = extra_args.to_vec();
+    if extra_args.iter().any(|arg| arg == "-S") {
+        if let Some(reason) = systemd_chroot_unusable_reason(root_mount_point) {
+            log::warn!("Not using arch-chroot -S, {}", reason);
+            extra_args.retain(|arg| arg != "-S");
+        }
+    }
+
+    // The secret has no business in the interactive shell
+    let mut command = Exec::cmd("arch-chroot")
+        .args(&extra_args)
+        .arg(root_mount_point)
+        .env(repair::ROOT_ENV, root_mount_point)
+        .env_remove(luks::PASSPHRASE_ENV);
+    if let Some(user) = user {
+        log::info!("Switching to user {}", user);
+        command = command.args(&["su", "--whitelist-environment", repair::ROOT_ENV, "-", user]);
+    }
+    if !extra_args.is_empty() {
+        log::info!("Running {}", command.to_cmdline_lossy());
+    }
+    // The exit status is the one of the last command run in the shell, so only failing to start
+    // arch-chroot counts as a failed chroot
+    match command.join() {
+        Ok(_) => true,
+        Err(err) => {
+            log::error!("Failed to chroot into root partition: {}", err);
+            false
+        },
+    }
+}
+
+/// Repairs requested on the command line, run inside the root once the interactive session ended
+fn run_post_chroot_repairs(args: &args::Args, root_mount_point: &str) {
+    if args.regen_initramfs {
+        match repair::initramfs_command(root_mount_point) {
+            Some(command) => {
+                repair::run_in_chroot(root_mount_point, command);
+            },
+            None => log::warn!("No initramfs generator found, skipping regenerating the initramfs"),
         }
-    } else {
-        user_input::get_btrfs_subvolume(device_name, &known_subvolumes)
+    }
+    if args.reinstall_bootloader {
+        reinstall_bootloader(root_mount_point);
+    }
+}
+
+fn reinstall_bootloader(root_mount_point: &str) {
+    // The bootloader commands install to the ESP, which needs the EFI variables of a UEFI boot
+    if !Path::new("/sys/firmware/efi").exists() {
+        log::warn!(
+            "The system is booted in BIOS mode, only UEFI installs are reinstalled by \
+             --reinstall-bootloader, skipping reinstalling the bootloader"
+        );
+        // Only GRUB needs a BIOS boot partition, and only on the disk it boots from
+        if repair::is_grub_installed(root_mount_point) {
+            let boot_device = repair::mounted_device(root_mount_point, "/boot")
+                .or_else(|| repair::mounted_device(root_mount_point, "/"));
+            if let Some(disk) = boot_device.as_deref().and_then(repair::parent_disk) {
+                if repair::missing_bios_boot_partition(&disk) {
+                    log::warn!(
+                        "{} has no BIOS boot partition, GRUB for BIOS can't be installed to a GPT \
+                         disk without one",
+                        disk
+                    );
+                }
+            }
+        }
+        return;
+    }
+    let Some(esp) = repair::find_esp(root_mount_point) else {
+        log::error!("No ESP is mounted in the root, skipping reinstalling the bootloader");
+        return;
     };
-    selected_subvolume
+    let Some((bootloader, commands)) = repair::bootloader_commands(root_mount_point, esp) else {
+        log::warn!("No supported bootloader found, skipping reinstalling the bootloader");
+        return;
+    };
+    log::info!("Reinstalling {} to the ESP at {}", bootloader, esp);
+    for command in &commands {
+        let command = command.iter().map(String::as_str).collect::<Vec<_>>();
+        if !repair::run_in_chroot(root_mount_point, &command) {
+            break;
+        }
+    }
 }
 
-fn list_block_devices(ignored_devices: Option<Vec<BlockDevice>>) -> Vec<BlockDevice> {
-    let disks_raw = Exec::cmd("lsblk")
-        .args(&[
-            "-f",
-            "-o",
-            "NAME,FSTYPE,UUID,PARTUUID,LABEL,PARTLABEL",
-            "-p",
-            "-a",
-            "-J",
-            "-Q",
-            "type=='part' || type=='crypt' && fstype!='swap' && fstype",
-        ])
-        .capture()
-        .expect("Failed to run lsblk")
-        .stdout_str();
-
-    let disks: block_device::BlockDevices =
-        serde_json::from_str(&disks_raw).expect("Failed to parse lsblk output");
-
-    let ignored_devices = ignored_devices.unwrap_or_default();
-    let block_devices = disks.block_devices;
-
-    if ignored_devices.is_empty() {
-        return block_devices;
-    }
-
-    block_devices.into_iter().filter(|d| !ignored_devices.contains(d)).collect()
+/// Set for the copy of the program running inside the mount namespace created for
+/// --mount-namespace, so it does not create another one
+const MOUNT_NAMESPACE_ENV: &str = "CACHY_CHROOT_IN_MOUNT_NAMESPACE";
+
+/// Runs the program again with the same arguments in a new mount namespace and exits with its exit
+/// code, mount propagation is made private so nothing mounted inside leaks to the host
+fn run_in_mount_namespace() -> ! {
+    if which("unshare").is_err() {
+        utils::print_error_and_exit("Command unshare not found, please install util-linux");
+    }
+    let exe = env::current_exe().unwrap_or_else(|err| {
+        utils::print_error_and_exit(&format!("Failed to find the program executable: {}", err))
+    });
+    log::info!("Creating a new mount namespace...");
+    let result = Exec::cmd("unshare")
+        .args(&["--mount", "--propagation", "private", "--"])
+        .arg(exe)
+        .args(&env::args_os().skip(1).collect::<Vec<_>>())
+        .env(MOUNT_NAMESPACE_ENV, "1")
+        .join();
+    match result {
+        Ok(ExitStatus::Exited(code)) => process::exit(code as i32),
+        _ => utils::print_error_and_exit("Failed to run in a new mount namespace"),
+    }
 }
 
 fn main() {
     let args = args::Args::parse();
 
     logger::init_logger().expect("Failed to initialize logger");
+    progress::set_progress_handler(|event| log::info!("{}", event));
+
+    if args.json {
+        utils::enable_json_errors();
+    }
+
+    if args.features {
+        println!("{}", depends::features_summary());
+        return;
+    }
+
+    if args.debug_lsblk {
+        let lsblk_json = cachy_chroot::lsblk_json()
+            .unwrap_or_else(|err| utils::print_error_and_exit(&err.to_string()));
+        print!("{}", lsblk_json);
+        return;
+    }
 
     if !Uid::effective().is_root() && !args.skip_root_check {
         utils::print_error_and_exit(
@@ -162,17 +600,16 @@ fn main() {
         );
     }
 
-    let depends = [
-        ("lsblk", "util-linux"),
-        ("mount", "util-linux"),
-        ("umount", "util-linux"),
-        ("arch-chroot", "arch-install-scripts"),
-        ("btrfs", "btrfs-progs"),
-        ("cryptsetup", "cryptsetup"),
-    ];
+    let features = Features {
+        btrfs: !args.no_btrfs,
+        luks: !args.no_luks,
+        image: args.image.is_some(),
+        genfstab: args.genfstab,
+        excluded_fs_types: args.exclude_fstypes.clone(),
+    };
 
-    for (cmd, pkg) in &depends {
-        if which(cmd).is_err() {
+    for (cmd, pkg, feature) in &depends::DEPENDS {
+        if features.is_enabled(*feature) && which(cmd).is_err() {
             utils::print_error_and_exit(&format!(
                 "Command {} not found, please install {}",
                 cmd, pkg
@@ -180,252 +617,503 @@ fn main() {
         }
     }
 
-    let mut block_devices = list_block_devices(None);
+    if args.mount_namespace && env::var_os(MOUNT_NAMESPACE_ENV).is_none() {
+        run_in_mount_namespace();
+    }
+
+    if args.partition_scan {
+        cachy_chroot::rescan_partitions();
+    }
+
+    if !args.wait_for_devices.is_empty() {
+        wait_for_devices(&args.wait_for_devices, Duration::from_secs(args.wait_timeout));
+    }
+
+    if args.enumerate_only {
+        // Errors still go to stderr, everything else would end up mixed into the JSON on stdout
+        log::set_max_level(log::LevelFilter::Error);
+        let block_devices = list_block_devices(&MountRegistry::default(), &features);
+        let enumeration = enumerate::enumerate(&block_devices, args.show_btrfs_dot_snapshots);
+        match serde_json::to_string_pretty(&enumeration) {
+            Ok(json) => println!("{}", json),
+            Err(err) => {
+                utils::print_error_and_exit(&format!("Failed to serialize devices: {}", err))
+            },
+        }
+        return;
+    }
+
+    if args.detect_os {
+        for device in list_block_devices(&MountRegistry::default(), &features)
+            .iter()
+            .filter(|device| os_release::LINUX_ROOT_FS_TYPES.contains(&device.fs_type.as_str()))
+        {
+            match cachy_chroot::detect_os(device) {
+                Ok(Some(os_release)) => println!("{}: {}", device.name, os_release),
+                Ok(None) => println!("{}: no OS found", device.name),
+                Err(err) => log::warn!("Unable to probe {}: {}", device.name, err),
+            }
+        }
+        return;
+    }
+
+    let arch_chroot_args = args
+        .arch_chroot_args
+        .as_deref()
+        .map(parse_arch_chroot_args)
+        .transpose()
+        .unwrap_or_else(|msg| utils::print_error_and_exit(&msg))
+        .unwrap_or_default();
+
+    if args.reuse_existing_mounts {
+        let root_mounts = mount::find_existing_root_mounts();
+        if root_mounts.is_empty() {
+            log::warn!("No already mounted root partitions found, continuing with selection");
+        } else if let Some(root_mount_point) = user_input::get_existing_root_mount(&root_mounts) {
+            // The mounts belong to the live system, so they are left in place after the chroot
+            if let Some(user) =
+                args.user.as_deref().filter(|user| !user_exists(&root_mount_point, user))
+            {
+                utils::print_error_and_exit(&format!(
+                    "User {} does not exist in {}",
+                    user, root_mount_point
+                ));
+            }
+            if chroot(&root_mount_point, args.user.as_deref(), &arch_chroot_args) {
+                run_post_chroot_repairs(&args, &root_mount_point);
+            }
+            return;
+        }
+    }
+
+    if args.force_umount && which("fuser").is_err() {
+        utils::print_error_and_exit("Command fuser not found, please install psmisc");
+    }
+
+    let luks_key_files =
+        luks::KeyFiles::parse(&args.luks_key_files, args.luks_key_file_all.clone())
+            .unwrap_or_else(|msg| utils::print_error_and_exit(&msg));
+
+    mount::set_mount_retry(
+        Duration::from_secs(args.mount_timeout),
+        Duration::from_secs(args.mount_retry_delay),
+    );
+
+    let device_options = mount::DeviceOptions::parse(&args.device_options)
+        .unwrap_or_else(|msg| utils::print_error_and_exit(&msg));
+
+    let mut registry = MountRegistry::default();
+    registry.force_umount = args.force_umount;
+    registry.no_cleanup = args.no_cleanup;
+    registry.luks_key_files = luks_key_files;
+    registry.device_options = device_options;
+
+    if let Some(image_path) = &args.image {
+        let attached_image = image::attach_image(image_path)
+            .unwrap_or_else(|err| exit_with_cleanup(&mut registry, &err.to_string()));
+        registry.attached_images.push(attached_image);
+    }
+
+    progress::emit(ProgressEvent::Enumerating);
+    let mut block_devices = list_block_devices(&registry, &features);
     let size = block_devices.len();
     log::info!("Found {} block devices", size);
 
     if size == 0 {
-        utils::print_error_and_exit("No block devices found on the system");
+        exit_with_cleanup(&mut registry, "No block devices found on the system");
     }
 
-    let mut mounted_partitions: Vec<String> = Vec::new();
-
     for disk in &block_devices {
         log::info!("Found partition: {}", disk.to_string());
     }
+    log::info!("Filesystems: {}", cachy_chroot::summarize_fs_types(&block_devices));
 
-    let mut selected_device = user_input::get_block_device("root", &block_devices, false)
-        .expect("No block device selected for root partition");
-    let mut discovered_btrfs_subvolumes: HashMap<String, Vec<BTRFSSubVolume>> =
-        HashMap::new();
-    let mut root_mount_options: Vec<String> = Vec::new();
-    let mut opened_luks_devices: Vec<BlockDevice> = Vec::new();
-    let mut has_luks_on_root = false;
+    let mut cache = DiscoveryCache::default();
 
-    if selected_device.fs_type == "crypto_LUKS" {
-        has_luks_on_root = true;
-        luks::open_device(selected_device);
-        opened_luks_devices.push(selected_device.clone());
-        block_devices = list_block_devices(Some(opened_luks_devices.to_owned()));
-        selected_device = user_input::get_block_device("root", &block_devices, false)
-            .expect("No block device selected for root partition");
-    }
+    // Only the first attempt is automatic, reselecting after a failed chroot would pick the same
+    // device again
+    let mut root_spec = args.root.as_deref();
+    let mut auto_root = args.auto_root;
+    let mut select_by_size = args.select_by_size;
+    loop {
+        let mut selected_device =
+            select_root_device(&block_devices, root_spec, auto_root, select_by_size, &mut registry);
+        let mut root_mount_options: Vec<String> = Vec::new();
+        let mut has_luks_on_root = false;
+        let mut read_only_root = false;
+        let mut root_subvolume_id = None;
+
+        // The decrypted device may be encrypted again, so keep unlocking until a mountable
+        // filesystem is picked
+        while selected_device.fs_type == "crypto_LUKS" {
+            has_luks_on_root = true;
+            let mapper_name =
+                cachy_chroot::unlock_luks_device(selected_device, None, &mut cache, &mut registry)
+                    .unwrap_or_else(|err| exit_with_cleanup(&mut registry, &err.to_string()));
+            block_devices = list_block_devices(&registry, &features);
+            // The device that was just unlocked is the root, asking again or selecting by partition
+            // type or size could pick a different one
+            let mapped_device_path = luks::mapped_device_path(&mapper_name);
+            selected_device = match block_devices.iter().find(|d| d.name == mapped_device_path) {
+                Some(device) => device,
+                None => select_root_device(&block_devices, None, false, false, &mut registry),
+            };
+        }
+
+        if mount::is_running_system_root(selected_device) {
+            exit_with_cleanup(
+                &mut registry,
+                &format!(
+                    "Partition {} is the root of the running system (e.g. the live medium), \
+                     chrooting into it makes no sense. Select the partition of the installed \
+                     system instead",
+                    selected_device.name
+                ),
+            );
+        }
+
+        // Checked before the BTRFS subvolume probe, which mounts the device too
+        if !confirm_mount_if_in_use(selected_device, &registry) {
+            exit_with_cleanup(&mut registry, "Aborted mounting the root partition");
+        }
+
+        let mut root_device = selected_device.clone();
+        if let Some(fs_type) = &args.root_fstype {
+            log::info!(
+                "Treating {} as {} instead of the detected filesystem type {:?}",
+                root_device.name,
+                fs_type,
+                root_device.fs_type
+            );
+            root_device.fs_type = fs_type.clone();
+        }
+        let selected_device = &root_device;
 
-    if selected_device.fs_type == "btrfs" {
-        root_mount_options.push("-o".to_owned());
-        log::info!("Selected BTRFS partition, mounting and listing subvolumes...");
+        if !confirm_unknown_filesystem(selected_device) {
+            exit_with_cleanup(&mut registry, "Aborted mounting the root partition");
+        }
+
+        if selected_device.fs_type == "btrfs" {
+            log::info!("Selected BTRFS partition, mounting and listing subvolumes...");
 
-        let selected_subvolume = get_btrfs_subvolume(
+            match btrfs::get_btrfs_subvolume(
+                selected_device,
+                &mut cache,
+                args.show_btrfs_dot_snapshots,
+                "root",
+            ) {
+                Ok(mut selected_subvolume) => {
+                    match read_only_snapshot_action(&selected_subvolume) {
+                        ReadOnlySnapshotAction::MountAsIs => {},
+                        ReadOnlySnapshotAction::MountReadOnly => {
+                            root_mount_options.push("ro".to_owned());
+                            read_only_root = true;
+                        },
+                        ReadOnlySnapshotAction::WritableCopy => {
+                            selected_subvolume =
+                                btrfs::create_writable_snapshot(&selected_subvolume)
+                                    .unwrap_or_else(|err| {
+                                        exit_with_cleanup(&mut registry, &err.to_string())
+                                    });
+                            cache.btrfs_subvolumes.remove(&selected_device.uuid);
+                        },
+                    }
+                    registry.mounted_partitions.push(selected_subvolume.get_id());
+                    root_mount_options
+                        .push(format!("subvolid={}", selected_subvolume.subvolume_id));
+                    root_subvolume_id = Some(selected_subvolume.subvolume_id);
+                },
+                Err(err @ ChrootError::SubvolumeList { requires_root: true, .. }) => {
+                    log::error!("{}, mounting the default subvolume instead", err);
+                    registry.mounted_partitions.push(selected_device.get_id());
+                },
+                Err(err) => exit_with_cleanup(&mut registry, &err.to_string()),
+            }
+        } else {
+            registry.mounted_partitions.push(selected_device.get_id());
+            root_mount_options.extend(xfs::ask_external_device_options(selected_device));
+        }
+
+        if let Some(mount_options) = &args.mount_options {
+            root_mount_options.push(mount_options.clone());
+        }
+
+        if !device_present(selected_device) {
+            block_devices = list_block_devices(&registry, &features);
+            registry.mounted_partitions.clear();
+            continue;
+        }
+
+        let mounted_root = cachy_chroot::mount_root(
             selected_device,
-            &mut discovered_btrfs_subvolumes,
-            args.show_btrfs_dot_snapshots,
-            "root",
-        );
-        mounted_partitions.push(selected_subvolume.get_id());
-        root_mount_options.push(format!("subvolid={}", selected_subvolume.subvolume_id));
-    } else {
-        mounted_partitions.push(selected_device.get_id());
-    }
+            args.root_fstype.as_deref(),
+            root_mount_options,
+            &mut registry,
+        )
+        .unwrap_or_else(|err| exit_with_cleanup(&mut registry, &err.to_string()));
+        registry.record_mount(selected_device, "/", root_subvolume_id);
+        // With --root-dir the OS tree lives below the mounted partition, everything else including
+        // the chroot is relative to that directory
+        let root_mount_point = match &args.root_dir {
+            None => mounted_root.clone(),
+            Some(root_dir) => match resolve_root_dir(&mounted_root, root_dir) {
+                Ok(root_mount_point) => root_mount_point,
+                Err(msg) => exit_with_cleanup(&mut registry, &msg),
+            },
+        };
+        let root_mount_point = root_mount_point.as_str();
 
-    let tmp_dir =
-        TempDir::with_prefix(format!("cachyos-chroot-root-mount-{}-", &selected_device.uuid))
-            .expect("Failed to create temporary directory");
-    let tmp_dir = tmp_dir.into_path();
-    let root_mount_point = tmp_dir.to_str().unwrap();
+        let ideal_fstab_path = Path::new(root_mount_point).join("etc").join("fstab");
+        let ideal_crypttab_path = Path::new(root_mount_point).join("etc").join("crypttab");
 
-    mount_block_device(selected_device, root_mount_point, false, Some(root_mount_options));
+        let crypttab_entries = luks::list_crypttab_entries(&ideal_crypttab_path, has_luks_on_root);
 
-    let ideal_fstab_path = Path::new(root_mount_point).join("etc").join("fstab");
-    let ideal_crypttab_path = Path::new(root_mount_point).join("etc").join("crypttab");
+        let missing_files = missing_root_files(root_mount_point, !args.genfstab);
+        if !missing_files.is_empty() {
+            let msg = format!(
+                "Unable to find {} in the root partition, is this a valid root partition?",
+                missing_files.join(", ")
+            );
+            if !args.keep_going {
+                exit_with_cleanup(
+                    &mut registry,
+                    &format!("{} Use --keep-going to continue anyway", msg),
+                );
+            }
+            log::warn!("{} Continuing because of --keep-going", msg);
+        }
 
-    let crypttab_entries = luks::list_crypttab_entries(&ideal_crypttab_path, has_luks_on_root);
+        match OsRelease::read(root_mount_point) {
+            Some(os_release) if os_release.is_arch_based() => {
+                log::info!("Detected {}", os_release);
+            },
+            Some(os_release) => log::warn!(
+                "Detected {}, which is not Arch based, arch-chroot and the repairs assume pacman \
+                 based tooling",
+                os_release
+            ),
+            None => log::warn!("Unable to detect the distribution of the root partition"),
+        }
 
-    if !ideal_fstab_path.exists() {
-        log::warn!(
-            "Unable to find /etc/fstab in the root partition, is this a valid root partition? \
-             Good luck fixing that!",
-        );
-    } else if !args.no_auto_mount {
-        log::info!("Mounting additional partitions based on /etc/fstab...");
-        let fstab = FsTab::new(&ideal_fstab_path);
-        let entries = fstab.get_entries().unwrap_or_default();
-        log::info!("Found {} entries in /etc/fstab", entries.len());
-        for entry in &entries {
-            if entry.vfs_type == "swap" {
-                continue;
+        if !ideal_fstab_path.exists() {
+            log::warn!(
+                "Unable to find /etc/fstab in the root partition, is this a valid root partition? \
+                 Use --genfstab to generate one from the mounted partitions",
+            );
+        } else {
+            // Remounting with the fstab options would mount a read-only snapshot read-write again
+            if selected_device.fs_type == "btrfs" && !read_only_root {
+                let user_options = registry
+                    .device_options
+                    .merge(selected_device, args.mount_options.clone().map(|options| vec![options]))
+                    .unwrap_or_default();
+                if let Err(err) = cachy_chroot::remount_root_from_fstab(
+                    &mounted_root,
+                    &ideal_fstab_path,
+                    &user_options,
+                ) {
+                    log::warn!("{}, continuing with default options", err);
+                }
             }
-            let device = if entry.fs_spec.starts_with("/dev") {
-                let crypttab_entry = crypttab_entries.get(&entry.fs_spec);
-                block_devices.iter().find(|d| {
-                    crypttab_entry == Some(&d.name)
-                        || crypttab_entry == Some(&d.uuid)
-                        || d.name == entry.fs_spec
-                })
-            } else {
-                let fs_spec = entry.fs_spec.split('=').collect::<Vec<_>>();
-                if fs_spec.len() != 2 {
-                    log::warn!("Invalid fs_spec in fstab, skipping...");
-                    continue;
+            if !args.no_auto_mount && !args.mount_root_only {
+                let result = cachy_chroot::mount_from_fstab(
+                    root_mount_point,
+                    &crypttab_entries,
+                    &mut cache,
+                    args.show_btrfs_dot_snapshots,
+                    &mut registry,
+                    |registry| list_block_devices(registry, &features),
+                    |_| !args.strict_fstab,
+                );
+                if let Err(err) = result {
+                    exit_with_cleanup(&mut registry, &err.to_string());
                 }
-                let fs_spec = fs_spec.last().unwrap();
-                block_devices.iter().find(|d| {
-                    d.uuid == *fs_spec
-                        || d.partuuid == Some(fs_spec.to_string())
-                        || d.label == Some(fs_spec.to_string())
-                        || d.partlabel == Some(fs_spec.to_string())
-                })
-            };
-            if device.is_none() {
-                log::warn!("Device {} not found, skipping mounting...", entry.fs_spec.yellow());
-                continue;
             }
-            let device = device.unwrap();
-            if mounted_partitions.contains(&device.get_id()) {
-                log::warn!("Partition {} already mounted, skipping...", entry.fs_spec.yellow());
-                continue;
+        }
+
+        for spec_path in &args.also_mount_roots {
+            mount_also_root(
+                spec_path,
+                &mut block_devices,
+                root_mount_point,
+                &mut cache,
+                args.show_btrfs_dot_snapshots,
+                &features,
+                &mut registry,
+            );
+        }
+
+        let mut used_mount_points: Vec<String> = Vec::new();
+        let mount_additional = !args.mount_root_only && user_input::mount_additional_partitions();
+        if mount_additional {
+            progress::emit(ProgressEvent::MountingExtras);
+            let mut selections =
+                user_input::get_additional_partitions(&block_devices, &used_mount_points);
+            // Parents have to be mounted before their children, e.g. /boot before /boot/efi
+            selections.sort_by_key(|(_, mount_point)| Path::new(mount_point).components().count());
+            for (mut selected_device, mount_point) in selections {
+                if !used_mount_points.contains(&mount_point) {
+                    used_mount_points.push(mount_point.clone());
+                }
+                if selected_device.fs_type == "crypto_LUKS" {
+                    let mapper_name = cachy_chroot::unlock_luks_device(
+                        &selected_device,
+                        luks::crypttab_mapper_name(&selected_device, &crypttab_entries),
+                        &mut cache,
+                        &mut registry,
+                    )
+                    .unwrap_or_else(|err| exit_with_cleanup(&mut registry, &err.to_string()));
+                    block_devices = list_block_devices(&registry, &features);
+                    let mapped_device_path = luks::mapped_device_path(&mapper_name);
+                    match block_devices.iter().find(|d| d.name == mapped_device_path) {
+                        Some(device) => selected_device = device.clone(),
+                        None => {
+                            log::warn!("Unable to find {}, skipping...", mapped_device_path);
+                            registry.record_skipped(&mount_point, "device not found");
+                            continue;
+                        },
+                    }
+                }
+                if !device_present(&selected_device) {
+                    block_devices = list_block_devices(&registry, &features);
+                    registry.record_skipped(&mount_point, "device not present");
+                    continue;
+                }
+                mount_additional_partition(
+                    &selected_device,
+                    &mount_point,
+                    root_mount_point,
+                    &mut cache,
+                    args.show_btrfs_dot_snapshots,
+                    &mut registry,
+                );
             }
-            let actual_mount_point = Path::new(root_mount_point)
-                .join(entry.mountpoint.to_str().unwrap().trim_start_matches('/'));
-            let actual_mount_point = actual_mount_point.to_str().unwrap();
-            if device.fs_type == "btrfs" {
-                let known_subvolumes = if discovered_btrfs_subvolumes.contains_key(&device.uuid) {
-                    discovered_btrfs_subvolumes.get(&device.uuid).unwrap().clone()
-                } else {
-                    let subvolumes = list_subvolumes(device, args.show_btrfs_dot_snapshots);
-                    discovered_btrfs_subvolumes.insert(device.uuid.clone(), subvolumes.clone());
-                    subvolumes
-                };
-                let fstab_opt_subvolume_id: Option<usize> =
-                    entry.mount_options.iter().find_map(|opt| {
-                        if opt.starts_with("subvolid=") {
-                            Some(opt.trim_start_matches("subvolid=").parse().unwrap())
-                        } else {
-                            None
+        }
+
+        // Answering no to mounting additional partitions skips the menu as well
+        if mount_additional {
+            loop {
+                match user_input::select_additional_mount_action() {
+                    AdditionalMountAction::Mount => {},
+                    AdditionalMountAction::Inspect => {
+                        if let Some(device) = user_input::get_device_to_inspect(&block_devices) {
+                            println!("{}", cachy_chroot::inspect_device(device));
                         }
-                    });
-                let fstab_opt_subvolume: Option<String> =
-                    entry.mount_options.iter().find_map(|opt| {
-                        if opt.starts_with("subvol=") {
-                            Some(opt.trim_start_matches("subvol=").to_string())
-                        } else {
-                            None
+                        continue;
+                    },
+                    AdditionalMountAction::UnmountLast => {
+                        match cachy_chroot::unmount_last(&mut registry, root_mount_point) {
+                            Some(mount_point) => log::info!("Unmounted {}", mount_point),
+                            None => log::warn!("No additional partition was unmounted"),
                         }
-                    });
-                let selected_subvolume = if let Some(subvolume_id) = fstab_opt_subvolume_id {
-                    known_subvolumes.iter().find(|subvol| subvol.subvolume_id == subvolume_id)
-                } else if let Some(subvolume_name) = fstab_opt_subvolume {
-                    known_subvolumes.iter().find(|subvol| {
-                        subvol.subvolume_name == subvolume_name
-                            || subvolume_name.strip_prefix('/').unwrap_or_default()
-                            == subvol.subvolume_name
-                    })
-                } else {
-                    log::warn!("No subvolume specified in fstab, using root subvolume");
-                    Some(&known_subvolumes[0])
-                };
-                if selected_subvolume.is_none() {
-                    log::warn!(
-                        "No subvolume found for entry: {} {}, skipping...",
-                        entry.fs_spec,
-                        entry.mountpoint.to_str().unwrap()
-                    );
+                        continue;
+                    },
+                    AdditionalMountAction::Refresh => {
+                        // Picks up disks plugged in since the list was made
+                        let previous_devices = std::mem::take(&mut block_devices);
+                        block_devices = list_block_devices(&registry, &features);
+                        for device in block_devices.iter().filter(|d| !previous_devices.contains(d))
+                        {
+                            log::info!("Found partition: {}", device);
+                        }
+                        log::info!("Found {} block devices", block_devices.len());
+                        continue;
+                    },
+                    AdditionalMountAction::Done => break,
+                }
+                let Some(mount_point) = user_input::get_mount_point(&used_mount_points) else {
                     continue;
+                };
+                if !used_mount_points.contains(&mount_point) {
+                    used_mount_points.push(mount_point.clone());
                 }
-                let selected_subvolume = selected_subvolume.unwrap();
-                if mounted_partitions.contains(&selected_subvolume.get_id()) {
-                    log::warn!(
-                        "Partition already mounted: {} {}, skipping...",
-                        entry.fs_spec,
-                        entry.mountpoint.to_str().unwrap()
-                    );
+                let selected_device =
+                    user_input::get_block_device(&mount_point, &block_devices, true);
+                if selected_device.is_none() {
                     continue;
                 }
-                if mount_block_device(
-                    &selected_subvolume.device,
-                    actual_mount_point,
-                    true,
-                    Some(vec![
-                        "-o".to_owned(),
-                        format!("subvolid={}", selected_subvolume.subvolume_id),
-                    ]),
-                ) {
-                    mounted_partitions.push(selected_subvolume.get_id());
+                let mut selected_device = selected_device.unwrap();
+                if selected_device.fs_type == "crypto_LUKS" {
+                    cachy_chroot::unlock_luks_device(
+                        selected_device,
+                        luks::crypttab_mapper_name(selected_device, &crypttab_entries),
+                        &mut cache,
+                        &mut registry,
+                    )
+                    .unwrap_or_else(|err| exit_with_cleanup(&mut registry, &err.to_string()));
+                    block_devices = list_block_devices(&registry, &features);
+                    let user_selection =
+                        user_input::get_block_device(&mount_point, &block_devices, true);
+                    if user_selection.is_none() {
+                        continue;
+                    }
+                    selected_device = user_selection.unwrap();
                 }
-                continue;
-            }
-            if mount_block_device(device, actual_mount_point, true, None) {
-                mounted_partitions.push(device.get_id());
+                if !device_present(selected_device) {
+                    block_devices = list_block_devices(&registry, &features);
+                    registry.record_skipped(&mount_point, "device not present");
+                    continue;
+                }
+                mount_additional_partition(
+                    selected_device,
+                    &mount_point,
+                    root_mount_point,
+                    &mut cache,
+                    args.show_btrfs_dot_snapshots,
+                    &mut registry,
+                );
             }
         }
-        log::info!("Finished mounting additional partitions");
-    }
 
-    while user_input::mount_additional_partitions() {
-        let mount_point = user_input::get_mount_point();
-        if mount_point.eq_ignore_ascii_case("skip") {
-            break;
-        }
-        let actual_mount_point =
-            Path::new(root_mount_point).join(mount_point.trim_start_matches('/'));
-        let actual_mount_point = actual_mount_point.to_str().unwrap();
-        let selected_device = user_input::get_block_device(&mount_point, &block_devices, true);
-        if selected_device.is_none() {
-            continue;
+        if args.genfstab {
+            write_generated_fstab(root_mount_point);
         }
-        let mut selected_device = selected_device.unwrap();
-        if selected_device.fs_type == "crypto_LUKS" {
-            luks::open_device(selected_device);
-            opened_luks_devices.push(selected_device.clone());
-            block_devices = list_block_devices(Some(opened_luks_devices.to_owned()));
-            let user_selection = user_input::get_block_device(&mount_point, &block_devices, true);
-            if user_selection.is_none() {
-                continue;
+
+        if let Some(output_fstab) = &args.output_fstab {
+            // The other roots belong to other installs, not to the one chrooted into
+            let also_mounted_roots = args
+                .also_mount_roots
+                .iter()
+                .filter_map(|spec_path| spec_path.split_once(':').map(|(_, path)| path))
+                .collect::<Vec<_>>();
+            let fstab =
+                cachy_chroot::render_fstab(&registry, root_mount_point, &also_mounted_roots);
+            match fs::write(output_fstab, fstab) {
+                Ok(()) => {
+                    log::info!("Wrote the fstab of the mounted partitions to {}", output_fstab)
+                },
+                Err(err) => log::error!("Failed to write {}: {}", output_fstab, err),
             }
-            selected_device = user_selection.unwrap();
         }
-        if mounted_partitions.contains(&selected_device.get_id()) {
-            log::warn!("Partition already mounted, skipping...");
-            continue;
+
+        if let Some(summary) = registry.skipped_summary() {
+            log::warn!("{}", summary);
         }
-        if selected_device.fs_type == "btrfs" {
-            let selected_subvolume = get_btrfs_subvolume(
-                selected_device,
-                &mut discovered_btrfs_subvolumes,
-                args.show_btrfs_dot_snapshots,
-                &mount_point,
+
+        if let Some(user) = args.user.as_deref().filter(|user| !user_exists(root_mount_point, user))
+        {
+            exit_with_cleanup(
+                &mut registry,
+                &format!("User {} does not exist in {}", user, root_mount_point),
             );
-            if mounted_partitions.contains(&selected_subvolume.get_id()) {
-                log::warn!("Partition already mounted, skipping...");
-                continue;
-            }
-            if mount_block_device(
-                &selected_subvolume.device,
-                actual_mount_point,
-                true,
-                Some(vec![
-                    "-o".to_owned(),
-                    format!("subvolid={}", selected_subvolume.subvolume_id),
-                ]),
-            ) {
-                mounted_partitions.push(selected_subvolume.get_id());
-            }
-            continue;
         }
-        if mount_block_device(selected_device, actual_mount_point, true, None) {
-            mounted_partitions.push(selected_device.get_id());
+
+        progress::emit(ProgressEvent::ReadyToChroot {
+            root_mount_point: root_mount_point.to_owned(),
+        });
+        if chroot(root_mount_point, args.user.as_deref(), &arch_chroot_args) {
+            run_post_chroot_repairs(&args, root_mount_point);
+            break;
         }
+        if !user_input::reselect_after_chroot_failure() {
+            break;
+        }
+        // LUKS devices stay open and probed devices stay cached for the next attempt
+        cachy_chroot::unmount_all(&mut registry);
+        root_spec = None;
+        auto_root = false;
+        select_by_size = false;
     }
 
-    log::info!("Chrooting into the configured root partition...");
-    log::info!("To exit the chroot, type 'exit' or press Ctrl+D");
-
-    Exec::cmd("arch-chroot")
-        .arg(root_mount_point)
-        .join()
-        .expect("Failed to chroot into root partition");
-
-    umount_block_device(root_mount_point, true);
-    for device in opened_luks_devices {
-        luks::close_device(&device);
-    }
+    cachy_chroot::cleanup(&mut registry);
 }
@@ -0,0 +1,527 @@
+use crate::block_device::BlockDevice;
+use crate::error::ChrootError;
+use crate::image::AttachedImage;
+use crate::luks::KeyFiles;
+
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use std::{fs, thread};
+
+use subprocess::{Exec, Redirection};
+use tempfile::TempDir;
+
+/// Returns the mount points the device is currently mounted at according to /proc/mounts, leaving
+/// out the ones made by this session
+pub fn find_existing_mounts(device: &BlockDevice, registry: &MountRegistry) -> Vec<String> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let parts = line.split_whitespace().collect::<Vec<_>>();
+            if parts.len() < 2 || parts[0] != device.name {
+                return None;
+            }
+            if registry.root_mount_point.as_ref().is_some_and(|root| parts[1].starts_with(root)) {
+                return None;
+            }
+            Some(parts[1].to_owned())
+        })
+        .collect()
+}
+
+/// Returns the mount points of block devices already mounted on the live system that look like a
+/// Linux root filesystem, e.g. a target left mounted at /mnt by a crashed installer
+pub fn find_existing_root_mounts() -> Vec<String> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    let mut root_mounts: Vec<String> = Vec::new();
+    for line in mounts.lines() {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if parts.len() < 2 || !parts[0].starts_with("/dev/") || parts[1] == "/" {
+            continue;
+        }
+        let mount_point = Path::new(parts[1]);
+        if mount_point.join("etc").join("os-release").exists()
+            && !root_mounts.iter().any(|root| root == parts[1])
+        {
+            root_mounts.push(parts[1].to_owned());
+        }
+    }
+    root_mounts
+}
+
+/// Where the running system keeps its root, archiso based live media mount the boot medium at
+/// /run/archiso/bootmnt and run from an overlay on top of the squashfs image stored on it
+const LIVE_SYSTEM_MOUNT_POINTS: [&str; 2] = ["/", "/run/archiso/bootmnt"];
+
+/// Returns the block devices backing the running system according to /proc/mounts, e.g. the live
+/// USB the tool is run from
+pub fn find_live_system_devices() -> Vec<String> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    let mut devices: Vec<String> = Vec::new();
+    for line in mounts.lines() {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if parts.len() < 2
+            || !parts[0].starts_with("/dev/")
+            || !LIVE_SYSTEM_MOUNT_POINTS.contains(&parts[1])
+        {
+            continue;
+        }
+        if !devices.iter().any(|device| device == parts[0]) {
+            devices.push(parts[0].to_owned());
+        }
+    }
+    devices
+}
+
+/// Whether the device is (part of) the root of the running system, either mounted at / itself or,
+/// when / is an overlay as on live media, mounted at one of the overlay's lower directories (e.g.
+/// the squashfs image of the live system)
+pub fn is_running_system_root(device: &BlockDevice) -> bool {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    let mounts = mounts.lines().map(|line| line.split_whitespace().collect::<Vec<_>>());
+    let mounts = mounts.filter(|parts| parts.len() >= 4).collect::<Vec<_>>();
+    let Some(root) = mounts.iter().rev().find(|parts| parts[1] == "/") else {
+        return false;
+    };
+    if root[0] == device.name {
+        return true;
+    }
+    let lower_dirs = root[3]
+        .split(',')
+        .filter_map(|opt| opt.strip_prefix("lowerdir="))
+        .flat_map(|dirs| dirs.split(':'))
+        .collect::<Vec<_>>();
+    mounts.iter().any(|parts| parts[0] == device.name && lower_dirs.contains(&parts[1]))
+}
+
+/// Builds the arguments for `mount`, all options are passed as a single comma separated `-o` as
+/// repeated `-o` flags are easy to get wrong when options are combined
+fn mount_args(
+    device_name: &str,
+    mount_point: &str,
+    fs_type: Option<&str>,
+    options: &[String],
+) -> Vec<String> {
+    let mut args = vec![device_name.to_owned(), mount_point.to_owned()];
+    if let Some(fs_type) = fs_type {
+        args.push("-t".to_owned());
+        args.push(fs_type.to_owned());
+    }
+    if !options.is_empty() {
+        args.push("-o".to_owned());
+        args.push(options.join(","));
+    }
+    args
+}
+
+/// How long and how often a mount failing with a transient error is retried
+struct MountRetry {
+    timeout: Duration,
+    delay: Duration,
+}
+
+static MOUNT_RETRY: OnceLock<MountRetry> = OnceLock::new();
+
+/// Sets up retrying mounts that fail with a transient error for up to `timeout`, only the first
+/// call has an effect
+pub fn set_mount_retry(timeout: Duration, delay: Duration) {
+    let _ = MOUNT_RETRY.set(MountRetry { timeout, delay });
+}
+
+/// Errors mount reports for devices that are not ready yet, e.g. a USB disk that is still spinning
+/// up
+const TRANSIENT_MOUNT_ERRORS: [&str; 3] = ["Input/output error", "not ready", "No medium found"];
+
+fn is_transient_mount_error(stderr: &str) -> bool {
+    TRANSIENT_MOUNT_ERRORS.iter().any(|error| stderr.contains(error))
+}
+
+/// Runs mount, retrying transient failures as configured with [`set_mount_retry`]
+fn run_mount(args: &[String]) -> bool {
+    let started = Instant::now();
+    loop {
+        let result = Exec::cmd("mount").args(args).stderr(Redirection::Pipe).capture();
+        let stderr = match result {
+            Ok(capture) if capture.success() => return true,
+            Ok(capture) => capture.stderr_str(),
+            Err(_) => return false,
+        };
+        let retry = MOUNT_RETRY.get().filter(|retry| {
+            is_transient_mount_error(&stderr) && started.elapsed() + retry.delay <= retry.timeout
+        });
+        let Some(retry) = retry else {
+            eprint!("{}", stderr);
+            return false;
+        };
+        log::warn!("{}, retrying in {} seconds...", stderr.trim(), retry.delay.as_secs());
+        thread::sleep(retry.delay);
+    }
+}
+
+/// Splits on the commas outside double quotes, values like SELinux contexts
+/// (`context="system_u:object_r:foo_t:s0:c1,c2"`) contain commas themselves
+fn split_mount_options(options: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (index, c) in options.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&options[start..index]);
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+    parts.push(&options[start..]);
+    parts
+}
+
+/// Splits options that still contain commas and trims stray whitespace, so lookups like
+/// `subvolid=` don't miss an option because of how it was written in fstab or on the command line
+pub fn normalize_mount_options(options: &[String]) -> Vec<String> {
+    options
+        .iter()
+        .flat_map(|opt| split_mount_options(opt))
+        .map(str::trim)
+        .filter(|opt| !opt.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Options only some filesystems accept, mount fails when they are passed to any other
+const FS_SPECIFIC_OPTIONS: [(&str, &[&str]); 8] = [
+    ("subvol", &["btrfs"]),
+    ("subvolid", &["btrfs"]),
+    ("compress", &["btrfs"]),
+    ("compress-force", &["btrfs"]),
+    ("space_cache", &["btrfs"]),
+    ("autodefrag", &["btrfs"]),
+    ("logdev", &["xfs"]),
+    ("rtdev", &["xfs"]),
+];
+
+/// Splits the options into the ones the filesystem accepts and the ones it doesn't, options that
+/// are not known to be filesystem specific are always kept
+fn filter_mount_options(fs_type: &str, options: Vec<String>) -> (Vec<String>, Vec<String>) {
+    options.into_iter().partition(|opt| {
+        let name = opt.split('=').next().unwrap_or_default();
+        FS_SPECIFIC_OPTIONS
+            .iter()
+            .find(|(option, _)| *option == name)
+            .is_none_or(|(_, fs_types)| fs_types.contains(&fs_type))
+    })
+}
+
+/// Mounts the device at the mount point, `options` are the individual mount options (e.g.
+/// `subvolid=256`) without `-o`
+pub fn mount_block_device(
+    device: &BlockDevice,
+    mount_point: &str,
+    options: Option<Vec<String>>,
+) -> Result<(), ChrootError> {
+    mount_block_device_as(device, None, mount_point, options)
+}
+
+/// Like [`mount_block_device`], but with `fs_type` the filesystem driver is forced with `-t`
+/// instead of letting mount detect it
+pub fn mount_block_device_as(
+    device: &BlockDevice,
+    fs_type: Option<&str>,
+    mount_point: &str,
+    options: Option<Vec<String>>,
+) -> Result<(), ChrootError> {
+    // --mount-options is passed on as a single comma separated entry
+    let mut options = normalize_mount_options(&options.unwrap_or_default());
+    // Without a known filesystem there is nothing to check the options against
+    let fs_type_name = fs_type.unwrap_or(&device.fs_type);
+    if !fs_type_name.is_empty() {
+        let (supported, dropped) = filter_mount_options(fs_type_name, options);
+        options = supported;
+        if !dropped.is_empty() {
+            log::warn!(
+                "Dropping mount options not supported by {}: {}",
+                fs_type_name,
+                dropped.join(",")
+            );
+        }
+    }
+    log::info!("Mounting partition {} at {} with options: {:?}", device.name, mount_point, options);
+    let args = mount_args(&device.name, mount_point, fs_type, &options);
+    if !run_mount(&args) {
+        return Err(ChrootError::Mount {
+            device: device.name.clone(),
+            mount_point: mount_point.to_owned(),
+        });
+    }
+    verify_mount(device, mount_point);
+    Ok(())
+}
+
+/// A successful `mount` does not guarantee the device landed at the mount point (e.g. with autofs
+/// or overlays), so check what findmnt reports as the source of the mount point
+fn verify_mount(device: &BlockDevice, mount_point: &str) {
+    let result = Exec::cmd("findmnt")
+        .args(&["--noheadings", "--first-only", "--output", "SOURCE", "--mountpoint", mount_point])
+        .capture();
+    let source = match result {
+        Ok(capture) if capture.success() => capture.stdout_str(),
+        _ => {
+            log::warn!("Unable to verify the mount at {}, findmnt did not find it", mount_point);
+            return;
+        },
+    };
+    // BTRFS subvolumes are reported as /dev/sdXN[/subvolume]
+    let source = source.trim().split('[').next().unwrap_or_default();
+    if source != device.name {
+        log::warn!(
+            "Expected {} to be mounted at {}, but findmnt reports {}",
+            device.name,
+            mount_point,
+            source
+        );
+    }
+}
+
+pub fn remount_block_device(mount_point: &str, options: &[String]) -> Result<(), ChrootError> {
+    let options = format!("remount,{}", options.join(","));
+    log::info!("Remounting partition at {} with options: {}", mount_point, options);
+    let result = Exec::cmd("mount").args(&["-o", &options, mount_point]).join();
+    if result.is_err() || !result.unwrap().success() {
+        return Err(ChrootError::Remount { mount_point: mount_point.to_owned() });
+    }
+    Ok(())
+}
+
+pub fn umount_block_device(mount_point: &str, recursive: bool) -> bool {
+    let args = if recursive { vec!["-R", mount_point] } else { vec![mount_point] };
+    log::info!("Unmounting partition at {}", mount_point);
+    let result = Exec::cmd("umount").args(&args).join();
+    if result.is_err() || !result.unwrap().success() {
+        log::warn!("Failed to unmount partition at {}", mount_point);
+        return false;
+    }
+    true
+}
+
+/// Creates a directory below the temporary directory to mount a device at, it is kept after this
+/// returns
+pub fn create_temp_mount_point(prefix: &str) -> Result<String, ChrootError> {
+    let tmp_dir = TempDir::with_prefix(prefix).map_err(|_| ChrootError::TempDir)?;
+    let tmp_dir = tmp_dir.into_path();
+    tmp_dir.to_str().map(str::to_owned).ok_or(ChrootError::TempDir)
+}
+
+/// Recursively unmounts everything at and below the mount point, `lazy` detaches it right away
+/// and lets the kernel clean up once it is no longer busy
+pub fn umount_tree(mount_point: &str, lazy: bool) -> bool {
+    let mut args = vec!["-R", mount_point];
+    if lazy {
+        args.push("-l");
+    }
+    log::info!("Unmounting {}{}", mount_point, if lazy { " lazily" } else { "" });
+    matches!(Exec::cmd("umount").args(&args).join(), Ok(status) if status.success())
+}
+
+/// Processes keeping the filesystem at the mount point busy according to fuser, as PID and command
+/// name
+pub fn find_mount_users(mount_point: &str) -> Vec<(u32, String)> {
+    let result =
+        Exec::cmd("fuser").args(&["-m", mount_point]).stderr(subprocess::NullFile).capture();
+    let Ok(capture) = result else {
+        return Vec::new();
+    };
+    // PIDs are followed by letters describing the kind of access, e.g. `1234c`
+    capture
+        .stdout_str()
+        .split_whitespace()
+        .filter_map(|pid| pid.trim_end_matches(|c: char| c.is_ascii_alphabetic()).parse().ok())
+        .map(|pid: u32| {
+            let command = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+            (pid, command.trim().to_owned())
+        })
+        .collect()
+}
+
+pub fn kill_mount_users(mount_point: &str) -> bool {
+    log::warn!("Killing processes using {}", mount_point);
+    matches!(Exec::cmd("fuser").args(&["-k", "-m", mount_point]).join(), Ok(status) if status.success())
+}
+
+/// Extra mount options passed with `--device-options DEVICE:OPTIONS`, merged into the options
+/// computed for matching devices wherever they are mounted
+#[derive(Default)]
+pub struct DeviceOptions {
+    per_device: Vec<(String, Vec<String>)>,
+}
+
+impl DeviceOptions {
+    pub fn parse(specs: &[String]) -> Result<Self, String> {
+        let per_device = specs
+            .iter()
+            .map(|spec| match spec.split_once(':') {
+                Some((device, options)) if !device.is_empty() && !options.is_empty() => {
+                    Ok((device.to_owned(), normalize_mount_options(&[options.to_owned()])))
+                },
+                _ => Err(format!("Invalid device options {}, expected DEVICE:OPTIONS", spec)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(DeviceOptions { per_device })
+    }
+
+    /// The extra options come after the computed ones, so they win where mount lets the last
+    /// occurrence of an option take effect
+    pub fn merge(&self, device: &BlockDevice, options: Option<Vec<String>>) -> Option<Vec<String>> {
+        let extra = self
+            .per_device
+            .iter()
+            .filter(|(spec, _)| device.matches_spec(spec))
+            .flat_map(|(_, options)| options.iter().cloned())
+            .collect::<Vec<_>>();
+        if extra.is_empty() {
+            return options;
+        }
+        let mut options = options.unwrap_or_default();
+        options.extend(extra);
+        Some(options)
+    }
+}
+
+/// A partition mounted during a session, `mount_point` is relative to the root
+pub struct MountEntry {
+    pub device: BlockDevice,
+    pub mount_point: String,
+    pub subvolume_id: Option<usize>,
+}
+
+/// Everything mounted or opened during a session, used to tear it down again afterwards
+#[derive(Default)]
+pub struct MountRegistry {
+    pub root_mount_point: Option<String>,
+    /// Offer to kill processes keeping the root busy on unmount instead of going straight to a
+    /// lazy unmount
+    pub force_umount: bool,
+    /// Leave everything mounted and opened on exit, for debugging
+    pub no_cleanup: bool,
+    /// Key files used instead of the passphrase prompt when opening LUKS devices
+    pub luks_key_files: KeyFiles,
+    /// Extra mount options per device from --device-options
+    pub device_options: DeviceOptions,
+    pub mounted_partitions: Vec<String>,
+    /// What ended up mounted where, in mount order
+    pub mount_table: Vec<MountEntry>,
+    /// Opened LUKS devices with the name of their mapping, in the order they were opened
+    pub opened_luks_devices: Vec<(BlockDevice, String)>,
+    /// Images attached for --image
+    pub attached_images: Vec<AttachedImage>,
+    /// Mount points that were not mounted with the reason why
+    pub skipped_mounts: Vec<(String, String)>,
+}
+
+impl MountRegistry {
+    pub fn record_mount(
+        &mut self,
+        device: &BlockDevice,
+        mount_point: &str,
+        subvolume_id: Option<usize>,
+    ) {
+        self.mount_table.push(MountEntry {
+            device: device.clone(),
+            mount_point: mount_point.to_owned(),
+            subvolume_id,
+        });
+    }
+
+    pub fn record_skipped(&mut self, mount_point: &str, reason: &str) {
+        self.skipped_mounts.push((mount_point.to_owned(), reason.to_owned()));
+    }
+
+    /// e.g. `Skipped: /home (device not found), /data (mount failed)`
+    pub fn skipped_summary(&self) -> Option<String> {
+        if self.skipped_mounts.is_empty() {
+            return None;
+        }
+        let skipped = self
+            .skipped_mounts
+            .iter()
+            .map(|(mount_point, reason)| format!("{} ({})", mount_point, reason))
+            .collect::<Vec<_>>();
+        Some(format!("Skipped: {}", skipped.join(", ")))
+    }
+
+    pub fn opened_luks_block_devices(&self) -> Vec<BlockDevice> {
+        self.opened_luks_devices.iter().map(|(device, _)| device.clone()).collect()
+    }
+}
+
+/// Best effort teardown when the registry goes away without `cleanup`, e.g. on a panic or an early
+/// return. `process::exit` skips it, so exiting on an error still needs an explicit `cleanup`.
+impl Drop for MountRegistry {
+    fn drop(&mut self) {
+        // Nobody is there to answer prompts while unwinding
+        if std::thread::panicking() {
+            self.force_umount = false;
+        }
+        crate::teardown(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_args_without_options() {
+        assert_eq!(mount_args("/dev/sda1", "/mnt", None, &[]), vec!["/dev/sda1", "/mnt"]);
+    }
+
+    #[test]
+    fn mount_args_join_options_into_one_flag() {
+        let options =
+            vec!["subvolid=256".to_owned(), "compress=zstd:1".to_owned(), "noatime".to_owned()];
+        assert_eq!(mount_args("/dev/sda1", "/mnt", None, &options), vec![
+            "/dev/sda1",
+            "/mnt",
+            "-o",
+            "subvolid=256,compress=zstd:1,noatime"
+        ]);
+    }
+
+    #[test]
+    fn normalize_keeps_quoted_commas() {
+        let options = vec![
+            r#"noatime, context="system_u:object_r:foo_t:s0:c1,c2""#.to_owned(),
+            "ro".to_owned(),
+        ];
+        assert_eq!(normalize_mount_options(&options), vec![
+            "noatime",
+            r#"context="system_u:object_r:foo_t:s0:c1,c2""#,
+            "ro"
+        ]);
+    }
+
+    #[test]
+    fn filter_keeps_quoted_options_whole() {
+        let options = normalize_mount_options(&[
+            r#"compress=zstd,context="system_u:object_r:foo_t:s0:c1,c2",noatime"#.to_owned(),
+        ]);
+        let (supported, dropped) = filter_mount_options("ext4", options);
+        assert_eq!(supported, vec![r#"context="system_u:object_r:foo_t:s0:c1,c2""#, "noatime"]);
+        assert_eq!(dropped, vec!["compress=zstd"]);
+    }
+
+    #[test]
+    fn mount_args_force_fs_type() {
+        let options = vec!["noatime".to_owned()];
+        assert_eq!(mount_args("/dev/sda1", "/mnt", Some("ext4"), &options), vec![
+            "/dev/sda1",
+            "/mnt",
+            "-t",
+            "ext4",
+            "-o",
+            "noatime"
+        ]);
+    }
+}
@@ -0,0 +1,57 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+/// Filesystems a Linux root can be installed on, the ones worth looking for an os-release file on
+pub const LINUX_ROOT_FS_TYPES: [&str; 7] =
+    ["ext4", "ext3", "ext2", "btrfs", "xfs", "f2fs", "bcachefs"];
+
+/// The distribution of a mounted root according to its os-release file
+#[derive(Serialize, Debug, PartialEq)]
+pub struct OsRelease {
+    pub id: String,
+    pub id_like: Vec<String>,
+    pub pretty_name: Option<String>,
+}
+
+impl OsRelease {
+    /// Reads /etc/os-release of the root, falling back to /usr/lib/os-release like systemd does
+    pub fn read(root_mount_point: &str) -> Option<Self> {
+        let root = Path::new(root_mount_point);
+        let contents = ["etc/os-release", "usr/lib/os-release"]
+            .iter()
+            .find_map(|path| fs::read_to_string(root.join(path)).ok())?;
+        Some(Self::parse(&contents))
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let value = |key: &str| {
+            contents.lines().find_map(|line| {
+                let (name, value) = line.trim().split_once('=')?;
+                (name == key).then(|| value.trim_matches(|c| c == '"' || c == '\'').to_owned())
+            })
+        };
+        OsRelease {
+            id: value("ID").unwrap_or_else(|| "linux".to_owned()),
+            id_like: value("ID_LIKE")
+                .map(|id_like| id_like.split_whitespace().map(str::to_owned).collect())
+                .unwrap_or_default(),
+            pretty_name: value("PRETTY_NAME"),
+        }
+    }
+
+    /// arch-chroot and the repairs run inside the chroot assume pacman based tooling
+    pub fn is_arch_based(&self) -> bool {
+        self.id == "arch" || self.id_like.iter().any(|id| id == "arch")
+    }
+}
+
+impl std::fmt::Display for OsRelease {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.pretty_name {
+            Some(pretty_name) => write!(f, "{} (ID={})", pretty_name, self.id),
+            None => write!(f, "ID={}", self.id),
+        }
+    }
+}
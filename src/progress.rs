@@ -0,0 +1,55 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Stages of setting up a chroot, so frontends embedding the crate (e.g. the installer) can show
+/// progress
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// Only for the initial listing, not for every refresh or poll of the device list
+    Enumerating,
+    UnlockingLuks {
+        device: String,
+    },
+    MountingRoot {
+        device: String,
+    },
+    MountingExtras,
+    ReadyToChroot {
+        root_mount_point: String,
+    },
+}
+
+impl fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProgressEvent::Enumerating => write!(f, "Enumerating block devices"),
+            ProgressEvent::UnlockingLuks { device } => {
+                write!(f, "Unlocking LUKS encrypted partition {}", device)
+            },
+            ProgressEvent::MountingRoot { device } => {
+                write!(f, "Mounting root partition {}", device)
+            },
+            ProgressEvent::MountingExtras => write!(f, "Mounting additional partitions"),
+            ProgressEvent::ReadyToChroot { root_mount_point } => {
+                write!(f, "Ready to chroot into {}", root_mount_point)
+            },
+        }
+    }
+}
+
+type ProgressHandler = Box<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+static PROGRESS_HANDLER: OnceLock<ProgressHandler> = OnceLock::new();
+
+/// Registers the callback receiving every [`ProgressEvent`], only the first call has an effect
+pub fn set_progress_handler(handler: impl Fn(&ProgressEvent) + Send + Sync + 'static) {
+    let _ = PROGRESS_HANDLER.set(Box::new(handler));
+}
+
+/// Without a registered handler the event is logged as a debug message
+pub fn emit(event: ProgressEvent) {
+    match PROGRESS_HANDLER.get() {
+        Some(handler) => handler(&event),
+        None => log::debug!("{}", event),
+    }
+}
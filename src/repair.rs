@@ -0,0 +1,131 @@
+use crate::luks;
+
+use std::fs;
+use std::path::Path;
+
+use subprocess::Exec;
+
+/// Set inside the chroot to the path of the root on the host, so scripts run in the chroot can e.g.
+/// tell what to mount from the host
+pub const ROOT_ENV: &str = "CACHY_CHROOT_ROOT";
+
+/// Initramfs generators in order of preference, with the command regenerating all images. CachyOS
+/// installs using Limine ship limine-mkinitcpio, which also updates the boot entries.
+const INITRAMFS_TOOLS: [(&str, &[&str]); 3] = [
+    ("limine-mkinitcpio", &["limine-mkinitcpio"]),
+    ("mkinitcpio", &["mkinitcpio", "-P"]),
+    ("dracut", &["dracut", "--regenerate-all", "--force"]),
+];
+
+fn is_installed(root_mount_point: &str, command: &str) -> bool {
+    Path::new(root_mount_point).join("usr").join("bin").join(command).exists()
+}
+
+/// Returns the command regenerating the initramfs with the tool installed in the root
+pub fn initramfs_command(root_mount_point: &str) -> Option<&'static [&'static str]> {
+    INITRAMFS_TOOLS
+        .iter()
+        .find(|(tool, _)| is_installed(root_mount_point, tool))
+        .map(|(_, command)| *command)
+}
+
+/// Partition type GUID of the BIOS boot partition GRUB embeds its core image in on GPT disks
+const BIOS_BOOT_PARTITION_TYPE: &str = "21686148-6449-6e6f-744e-656564454649";
+
+/// Returns the disk the device lives on (e.g. `/dev/sda` for `/dev/sda2`), following LUKS mappings
+/// down to the partition they were opened from
+pub fn parent_disk(device_name: &str) -> Option<String> {
+    let result = Exec::cmd("lsblk").args(&["-n", "-s", "-p", "-r", "-o", "NAME,TYPE", device_name]);
+    let output = result.capture().map(|capture| capture.stdout_str()).unwrap_or_default();
+    output.lines().find_map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+        [name, "disk"] => Some(name.to_owned()),
+        _ => None,
+    })
+}
+
+/// Whether the disk has a GPT but no BIOS boot partition. Partitions without a filesystem like the
+/// BIOS boot partition may not be listed for selection, so lsblk is asked again.
+pub fn missing_bios_boot_partition(disk: &str) -> bool {
+    let result = Exec::cmd("lsblk").args(&["-n", "-r", "-o", "PTTYPE,PARTTYPE", disk]).capture();
+    let output = result.map(|capture| capture.stdout_str()).unwrap_or_default();
+    let rows = output.lines().map(|line| line.split_whitespace().collect::<Vec<_>>());
+    let rows = rows.collect::<Vec<_>>();
+    let has_gpt = rows.iter().any(|row| row.first() == Some(&"gpt"));
+    let has_bios_boot = rows.iter().any(|row| {
+        row.get(1).is_some_and(|parttype| parttype.eq_ignore_ascii_case(BIOS_BOOT_PARTITION_TYPE))
+    });
+    has_gpt && !has_bios_boot
+}
+
+/// Returns the device mounted at the path below the root, according to /proc/mounts
+pub fn mounted_device(root_mount_point: &str, path: &str) -> Option<String> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    let target = Path::new(root_mount_point).join(path.trim_start_matches('/'));
+    mounts.lines().rev().find_map(|line| {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        (parts.len() >= 2 && Path::new(parts[1]) == target).then(|| parts[0].to_owned())
+    })
+}
+
+/// GRUB counts as the bootloader when the root has grub-install and a /boot/grub directory
+pub fn is_grub_installed(root_mount_point: &str) -> bool {
+    is_installed(root_mount_point, "grub-install")
+        && Path::new(root_mount_point).join("boot").join("grub").exists()
+}
+
+/// Where an ESP is usually mounted, relative to the root
+const ESP_MOUNT_POINTS: [&str; 3] = ["/boot/efi", "/efi", "/boot"];
+
+/// Returns where below the root the ESP is mounted, according to /proc/mounts
+pub fn find_esp(root_mount_point: &str) -> Option<&'static str> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    ESP_MOUNT_POINTS.into_iter().find(|esp| {
+        let target = Path::new(root_mount_point).join(esp.trim_start_matches('/'));
+        mounts.lines().any(|line| {
+            let parts = line.split_whitespace().collect::<Vec<_>>();
+            parts.len() >= 3 && Path::new(parts[1]) == target && parts[2] == "vfat"
+        })
+    })
+}
+
+/// Returns the bootloader installed in the root with the commands reinstalling it to the ESP, only
+/// UEFI installs are handled
+pub fn bootloader_commands(
+    root_mount_point: &str,
+    esp: &str,
+) -> Option<(&'static str, Vec<Vec<String>>)> {
+    let root = Path::new(root_mount_point);
+    let esp_path = root.join(esp.trim_start_matches('/'));
+    let command = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    if is_installed(root_mount_point, "limine-update") {
+        return Some(("Limine", vec![command(&["limine-update"])]));
+    }
+    if is_installed(root_mount_point, "bootctl") && esp_path.join("loader").exists() {
+        let esp_arg = format!("--esp-path={}", esp);
+        return Some(("systemd-boot", vec![command(&["bootctl", "install", &esp_arg])]));
+    }
+    if is_grub_installed(root_mount_point) {
+        let esp_arg = format!("--efi-directory={}", esp);
+        return Some(("GRUB", vec![
+            command(&["grub-install", &esp_arg]),
+            command(&["grub-mkconfig", "-o", "/boot/grub/grub.cfg"]),
+        ]));
+    }
+    None
+}
+
+/// Runs a command non-interactively inside the root with arch-chroot
+pub fn run_in_chroot(root_mount_point: &str, command: &[&str]) -> bool {
+    log::info!("Running {} in {}", command.join(" "), root_mount_point);
+    let result = Exec::cmd("arch-chroot")
+        .arg(root_mount_point)
+        .args(command)
+        .env(ROOT_ENV, root_mount_point)
+        .env_remove(luks::PASSPHRASE_ENV)
+        .join();
+    if !matches!(result, Ok(status) if status.success()) {
+        log::error!("Failed to run {}", command.join(" "));
+        return false;
+    }
+    true
+}
@@ -2,26 +2,106 @@ use crate::block_device;
 
 use colored::Colorize;
 use dialoguer::theme::ColorfulTheme;
-use dialoguer::{Confirm, Input, Select};
+use dialoguer::{Confirm, FuzzySelect, Input, MultiSelect, Select};
 
-fn confirm_user_action<'a>(prompt_text: &'a str, theme: &'a ColorfulTheme) -> Confirm<'a> {
+/// Cancelling the prompt with Esc or q, or failing to read the answer, counts as answering no
+fn confirm_user_action(prompt_text: &str, theme: &ColorfulTheme) -> bool {
     Confirm::with_theme(theme)
         .with_prompt(prompt_text)
         .default(false)
         .show_default(false)
         .wait_for_newline(true)
+        .interact_opt()
+        .ok()
+        .flatten()
+        .unwrap_or(false)
 }
 
 pub fn mount_additional_partitions() -> bool {
     confirm_user_action("Do you want to mount additional partitions?", &ColorfulTheme::default())
-        .interact()
-        .unwrap()
 }
 
 pub fn continue_on_mount_failure() -> bool {
     confirm_user_action("Do you want to skip mounting this partition?", &ColorfulTheme::default())
-        .interact()
-        .unwrap()
+}
+
+pub fn write_generated_fstab(overwrite: bool) -> bool {
+    let prompt = if overwrite {
+        "Do you want to replace /etc/fstab with the generated one? (a backup is kept as \
+         /etc/fstab.bak)"
+    } else {
+        "Do you want to write the generated fstab to /etc/fstab?"
+    };
+    confirm_user_action(prompt, &ColorfulTheme::default())
+}
+
+pub fn reselect_after_chroot_failure() -> bool {
+    confirm_user_action(
+        "Chroot failed, do you want to unmount and select the root partition again?",
+        &ColorfulTheme::default(),
+    )
+}
+
+pub fn confirm_force_umount(target: &str, users: &[(u32, String)]) -> bool {
+    let users =
+        users.iter().map(|(pid, command)| format!("{} ({})", pid, command)).collect::<Vec<_>>();
+    confirm_user_action(
+        &format!(
+            "{} is still in use by {}, do you want to kill these processes to unmount it?",
+            target.yellow(),
+            users.join(", ")
+        ),
+        &ColorfulTheme::default(),
+    )
+}
+
+/// Shared by everything that unlocks with a passphrase, `kind` names what is unlocked (e.g. `LUKS
+/// encrypted partition`)
+pub fn retry_passphrase(kind: &str, name: &str) -> bool {
+    confirm_user_action(
+        &format!(
+            "Unable to unlock {} {} with the given passphrase or key file, do you want to enter \
+             the passphrase again?",
+            kind,
+            name.yellow()
+        ),
+        &ColorfulTheme::default(),
+    )
+}
+
+pub fn mount_device_in_use(device_name: &str, mount_points: &[String]) -> bool {
+    confirm_user_action(
+        &format!(
+            "Partition {} is already mounted at {}, mounting it again may cause data corruption. \
+             Do you want to mount it anyway?",
+            device_name.yellow(),
+            mount_points.join(", ")
+        ),
+        &ColorfulTheme::default(),
+    )
+}
+
+pub fn mount_unknown_filesystem(device_name: &str) -> bool {
+    confirm_user_action(
+        &format!(
+            "Partition {} has no recognized filesystem, do you want to try mounting it anyway?",
+            device_name.yellow()
+        ),
+        &ColorfulTheme::default(),
+    )
+}
+
+pub fn get_existing_root_mount(root_mounts: &[String]) -> Option<String> {
+    let index = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select an already mounted root to chroot into (use arrow keys): ")
+        .default(0)
+        .max_length(10)
+        .items(root_mounts)
+        .item("Mount a root partition instead")
+        .interact_opt()
+        .ok()
+        .flatten()?;
+    root_mounts.get(index).cloned()
 }
 
 pub fn use_cachyos_btrfs_preset() -> bool {
@@ -29,41 +109,236 @@ pub fn use_cachyos_btrfs_preset() -> bool {
         "Do you want to use CachyOS BTRFS preset to auto mount root subvolume?",
         &ColorfulTheme::default(),
     )
-        .interact()
-        .unwrap()
 }
 
-pub fn get_mount_point() -> String {
-    Input::with_theme(&ColorfulTheme::default())
-        .with_prompt(
-            "Enter the mount point for additional partition (e.g. /boot) type 'skip' to cancel: ",
-        )
-        .validate_with(|input: &String| -> Result<(), &'static str> {
-            if input.starts_with('/') || input.eq_ignore_ascii_case("skip") {
+pub enum ReadOnlySnapshotAction {
+    WritableCopy,
+    MountReadOnly,
+    MountAsIs,
+}
+
+pub fn select_read_only_snapshot_action(subvolume_name: &str) -> ReadOnlySnapshotAction {
+    let index = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!(
+            "Subvolume {} is a read-only snapshot, how do you want to mount it? (use arrow keys): ",
+            subvolume_name.yellow()
+        ))
+        .default(0)
+        .items(&[
+            "Create a writable copy with btrfs subvolume snapshot and mount that",
+            "Mount it read-only",
+            "Mount it as is",
+        ])
+        .interact_opt()
+        .ok()
+        .flatten();
+    match index {
+        Some(0) => ReadOnlySnapshotAction::WritableCopy,
+        Some(1) => ReadOnlySnapshotAction::MountReadOnly,
+        _ => ReadOnlySnapshotAction::MountAsIs,
+    }
+}
+
+pub fn select_missing_btrfs_subvolume(mount_point: &str) -> bool {
+    confirm_user_action(
+        &format!(
+            "Subvolume for {} from /etc/fstab was not found, do you want to select one manually?",
+            mount_point.yellow()
+        ),
+        &ColorfulTheme::default(),
+    )
+}
+
+pub fn use_xfs_external_devices(device_name: &str) -> bool {
+    confirm_user_action(
+        &format!(
+            "Does the XFS partition {} use an external log or realtime device?",
+            device_name.yellow()
+        ),
+        &ColorfulTheme::default(),
+    )
+}
+
+/// Returns `None` if the input was left empty or cancelled
+pub fn get_xfs_external_device(kind: &str) -> Option<String> {
+    let device: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("Enter the {} device (e.g. /dev/sdb1), leave empty for none: ", kind))
+        .allow_empty(true)
+        .validate_with(|input: &String| {
+            if input.is_empty() || input.starts_with("/dev/") {
                 Ok(())
             } else {
-                Err("Mount point must start with /")
+                Err("Device must start with /dev/")
             }
         })
+        .interact_text()
+        .ok()?;
+    Some(device).filter(|device| !device.is_empty())
+}
+
+fn validate_mount_point(input: &str) -> Result<(), &'static str> {
+    if input.starts_with('/') {
+        Ok(())
+    } else {
+        Err("Mount point must start with /")
+    }
+}
+
+const COMMON_MOUNT_POINTS: [&str; 4] = ["/home", "/boot", "/boot/efi", "/var"];
+
+fn select_mount_point(
+    prompt: &str,
+    suggested_mount_point: Option<&str>,
+    used_mount_points: &[String],
+    allow_skip: bool,
+) -> Option<String> {
+    let mut suggestions = suggested_mount_point.map(str::to_owned).into_iter().collect::<Vec<_>>();
+    for mount_point in used_mount_points {
+        if !suggestions.contains(mount_point) {
+            suggestions.push(mount_point.clone());
+        }
+    }
+    for mount_point in COMMON_MOUNT_POINTS {
+        if !suggestions.iter().any(|used| used == mount_point) {
+            suggestions.push(mount_point.to_owned());
+        }
+    }
+    let default_theme = ColorfulTheme::default();
+    let mut select = Select::with_theme(&default_theme)
+        .with_prompt(prompt)
+        .default(0)
+        .max_length(10)
+        .items(&suggestions)
+        .item("Custom...");
+    if allow_skip {
+        select = select.item("Skip");
+    }
+    let index = select.interact_opt().ok().flatten()?;
+    if index < suggestions.len() {
+        return Some(suggestions.swap_remove(index));
+    }
+    if index > suggestions.len() {
+        return None;
+    }
+    Input::with_theme(&default_theme)
+        .with_prompt("Enter the mount point (e.g. /boot): ")
+        .validate_with(|input: &String| validate_mount_point(input))
         .interact()
-        .unwrap()
+        .ok()
 }
 
-pub fn get_btrfs_subvolume(
-    partition_name: &str,
-    subvolumes: &[block_device::BTRFSSubVolume],
-) -> block_device::BTRFSSubVolume {
+pub enum AdditionalMountAction {
+    Mount,
+    Inspect,
+    UnmountLast,
+    Refresh,
+    Done,
+}
+
+pub fn select_additional_mount_action() -> AdditionalMountAction {
     let index = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt(format!(
-            "Select the subvolume for the {} partition (use arrow keys): ",
-            partition_name.yellow()
-        ))
+        .with_prompt("What do you want to do next? (use arrow keys): ")
         .default(0)
+        .items(&[
+            "Mount an additional partition",
+            "Inspect a partition",
+            "Unmount the last mounted partition",
+            "Refresh the device list",
+            "Done",
+        ])
+        .interact_opt()
+        .ok()
+        .flatten();
+    match index {
+        Some(0) => AdditionalMountAction::Mount,
+        Some(1) => AdditionalMountAction::Inspect,
+        Some(2) => AdditionalMountAction::UnmountLast,
+        Some(3) => AdditionalMountAction::Refresh,
+        _ => AdditionalMountAction::Done,
+    }
+}
+
+/// Returns `None` if the user skipped or cancelled the prompt
+pub fn get_mount_point(used_mount_points: &[String]) -> Option<String> {
+    select_mount_point(
+        "Select the mount point for additional partition (use arrow keys): ",
+        None,
+        used_mount_points,
+        true,
+    )
+}
+
+pub fn get_additional_partitions(
+    block_devices: &[block_device::BlockDevice],
+    used_mount_points: &[String],
+) -> Vec<(block_device::BlockDevice, String)> {
+    let indices = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt(
+            "Select the block devices to mount (use space to select, enter to confirm, select \
+             none to mount partitions one at a time): ",
+        )
         .max_length(10)
-        .items(subvolumes)
-        .interact()
-        .unwrap();
-    subvolumes[index].clone()
+        .items(block_devices)
+        .interact_opt()
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    // Devices whose mount point prompt was cancelled are left out
+    indices
+        .into_iter()
+        .filter_map(|index| {
+            let device = &block_devices[index];
+            let mount_point = select_mount_point(
+                &format!("Select the mount point for {} (use arrow keys): ", device.name.yellow()),
+                device.suggested_mount_point(),
+                used_mount_points,
+                false,
+            )?;
+            Some((device.clone(), mount_point))
+        })
+        .collect()
+}
+
+/// Lists that don't fit on one page get a searchable prompt instead, so they can be filtered by
+/// typing
+const FUZZY_SELECT_THRESHOLD: usize = 10;
+
+/// Returns `None` if the prompt was cancelled with Esc or q, or could not be shown
+fn select_item(prompt: &str, items: &[String], default: Option<usize>) -> Option<usize> {
+    let default_theme = ColorfulTheme::default();
+    if items.len() > FUZZY_SELECT_THRESHOLD {
+        let mut select = FuzzySelect::with_theme(&default_theme)
+            .with_prompt(format!("{} (type to search): ", prompt))
+            .max_length(10)
+            .items(items);
+        if let Some(default) = default {
+            select = select.default(default);
+        }
+        return select.interact_opt().ok().flatten();
+    }
+    let mut select = Select::with_theme(&default_theme)
+        .with_prompt(format!("{} (use arrow keys): ", prompt))
+        .max_length(10)
+        .items(items);
+    if let Some(default) = default {
+        select = select.default(default);
+    }
+    select.interact_opt().ok().flatten()
+}
+
+pub fn get_btrfs_subvolume(
+    partition_name: &str,
+    subvolumes: &[block_device::BTRFSSubVolume],
+    usage: Option<&block_device::BTRFSUsage>,
+) -> Option<block_device::BTRFSSubVolume> {
+    let items = subvolumes.iter().map(ToString::to_string).collect::<Vec<_>>();
+    let mut prompt = format!("Select the subvolume for the {} partition", partition_name.yellow());
+    // Helps deciding whether e.g. a system upgrade in the chroot will fit
+    if let Some(usage) = usage {
+        prompt.push_str(&format!(" ({})", usage));
+    }
+    let index = select_item(&prompt, &items, Some(0))?;
+    subvolumes.get(index).cloned()
 }
 
 pub fn get_block_device<'a>(
@@ -71,17 +346,34 @@ pub fn get_block_device<'a>(
     block_devices: &'a [block_device::BlockDevice],
     allow_skip: bool,
 ) -> Option<&'a block_device::BlockDevice> {
-    let default_theme = ColorfulTheme::default();
-    let prompt = Select::with_theme(&default_theme)
-        .with_prompt(format!(
-            "Select the block device for the {} partition (use arrow keys): ",
-            partition_name.yellow()
-        ))
-        .default(0)
-        .max_length(10)
-        .items(block_devices);
-    let index =
-        if allow_skip { prompt.item("Skip").interact().ok()? } else { prompt.interact().ok()? };
+    select_block_device(
+        &format!("Select the block device for the {} partition", partition_name.yellow()),
+        block_devices,
+        allow_skip,
+    )
+}
+
+pub fn get_device_to_inspect(
+    block_devices: &[block_device::BlockDevice],
+) -> Option<&block_device::BlockDevice> {
+    select_block_device("Select the block device to inspect", block_devices, true)
+}
+
+fn select_block_device<'a>(
+    prompt: &str,
+    block_devices: &'a [block_device::BlockDevice],
+    allow_skip: bool,
+) -> Option<&'a block_device::BlockDevice> {
+    let mut items = block_devices.iter().map(ToString::to_string).collect::<Vec<_>>();
+    // Without a skip option nothing is preselected, so a hurried enter can't pick e.g. the ESP as
+    // root
+    let default = if allow_skip {
+        items.push("Skip".to_owned());
+        Some(0)
+    } else {
+        None
+    };
+    let index = select_item(prompt, &items, default)?;
     if index == block_devices.len() {
         return None;
     }
@@ -1,6 +1,17 @@
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub fn print_error_and_exit(msg: &str) {
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Makes `print_error_and_exit` also emit a JSON error object on stderr
+pub fn enable_json_errors() {
+    JSON_ERRORS.store(true, Ordering::Relaxed);
+}
+
+pub fn print_error_and_exit(msg: &str) -> ! {
     log::error!("{msg}");
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        eprintln!("{}", serde_json::json!({ "error": msg, "code": 1 }));
+    }
     exit(1);
 }
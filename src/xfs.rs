@@ -0,0 +1,31 @@
+use crate::block_device::BlockDevice;
+use crate::mount::normalize_mount_options;
+use crate::user_input;
+
+/// Mount options pointing XFS at an external log or realtime device, without them a filesystem
+/// using one can't be mounted
+const EXTERNAL_DEVICE_OPTIONS: [&str; 2] = ["logdev=", "rtdev="];
+
+/// Picks the external log/realtime device options out of the options of an fstab entry
+pub fn external_device_options(options: &[String]) -> Vec<String> {
+    normalize_mount_options(options)
+        .into_iter()
+        .filter(|opt| EXTERNAL_DEVICE_OPTIONS.iter().any(|prefix| opt.starts_with(prefix)))
+        .collect()
+}
+
+/// Asks for the external log/realtime devices of an XFS partition mounted interactively, as
+/// nothing on the partition itself tells where they are
+pub fn ask_external_device_options(device: &BlockDevice) -> Vec<String> {
+    if device.fs_type != "xfs" || !user_input::use_xfs_external_devices(&device.name) {
+        return Vec::new();
+    }
+    let mut options = Vec::new();
+    if let Some(log_device) = user_input::get_xfs_external_device("log") {
+        options.push(format!("logdev={}", log_device));
+    }
+    if let Some(rt_device) = user_input::get_xfs_external_device("realtime") {
+        options.push(format!("rtdev={}", rt_device));
+    }
+    options
+}